@@ -0,0 +1,299 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use tonic::{
+    Request, Status,
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// 已认证的用户, 由 [`Authenticator`] 放入请求的 extensions, 服务以此为准而不信任 metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedUser {
+    pub username: String,
+}
+
+/// Token 中的 claims, `sub` 为用户名.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Claims {
+    pub sub: String,
+    /// 过期时间, Unix 秒
+    pub exp: u64,
+}
+
+/// 服务端拦截器, 从以下来源得到用户名:
+///
+/// - 启用 mTLS 时, 取自客户端证书的 CN, 没有 CN 时取第一个 SAN
+/// - 配置了密钥时, 校验 `authorization: Bearer <token>`(HMAC 签名的 JWT), 取自 claims
+///
+/// 两者都启用时用户名必须一致, 都未启用时不做认证.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    jwt: Option<Arc<(DecodingKey, Validation)>>,
+    mtls: bool,
+}
+
+impl Authenticator {
+    /// 不做认证, 用户名取自客户端的 metadata.
+    pub fn disabled() -> Self {
+        Authenticator::default()
+    }
+
+    /// 使用 HS256 密钥校验 token.
+    pub fn with_hmac_secret(self, secret: &[u8]) -> Self {
+        let key = DecodingKey::from_secret(secret);
+        let validation = Validation::new(Algorithm::HS256);
+        Authenticator {
+            jwt: Some(Arc::new((key, validation))),
+            ..self
+        }
+    }
+
+    /// 用户名取自客户端证书, 服务端需要配置 client CA 要求客户端出示证书.
+    pub fn with_client_certificate(self) -> Self {
+        Authenticator { mtls: true, ..self }
+    }
+
+    fn user_from_token(
+        jwt: &(DecodingKey, Validation),
+        request: &Request<()>,
+    ) -> Result<String, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .ok_or(Status::unauthenticated("no authorization in metadata"))?
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(Status::unauthenticated(
+                "authorization is not a bearer token",
+            ))?;
+        let (key, validation) = jwt;
+        let claims = jsonwebtoken::decode::<Claims>(token, key, validation)
+            .map_err(|err| Status::unauthenticated(format!("invalid token: {err}")))?
+            .claims;
+        Ok(claims.sub)
+    }
+
+    fn user_from_certificate(request: &Request<()>) -> Result<String, Status> {
+        let certs = request
+            .peer_certs()
+            .ok_or(Status::unauthenticated("no client certificate"))?;
+        let leaf = certs
+            .first()
+            .ok_or(Status::unauthenticated("no client certificate"))?;
+        username_from_certificate(leaf)
+    }
+}
+
+/// 从 DER 编码的证书中取用户名: subject 的 CN, 没有 CN 时取第一个 DNS 或 email SAN.
+pub fn username_from_certificate(der: &[u8]) -> Result<String, Status> {
+    let (_, cert) = X509Certificate::from_der(der)
+        .map_err(|err| Status::unauthenticated(format!("invalid client certificate: {err}")))?;
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+    {
+        return Ok(cn.to_owned());
+    }
+    let san = cert
+        .subject_alternative_name()
+        .map_err(|err| Status::unauthenticated(format!("invalid client certificate: {err}")))?;
+    san.into_iter()
+        .flat_map(|san| san.value.general_names.iter())
+        .find_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+            _ => None,
+        })
+        .ok_or(Status::unauthenticated(
+            "no common name or subject alternative name in client certificate",
+        ))
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let cert_user = match self.mtls {
+            true => Some(Self::user_from_certificate(&request)?),
+            false => None,
+        };
+        let token_user = match &self.jwt {
+            Some(jwt) => Some(Self::user_from_token(jwt, &request)?),
+            None => None,
+        };
+        let username = match (cert_user, token_user) {
+            (Some(cert_user), Some(token_user)) if cert_user != token_user => {
+                return Err(Status::permission_denied(
+                    "token does not match client certificate",
+                ));
+            }
+            (Some(username), _) | (None, Some(username)) => username,
+            (None, None) => return Ok(request),
+        };
+
+        if let Some(metadata_username) = request.metadata().get("username")
+            && metadata_username.to_str().ok() != Some(username.as_str())
+        {
+            return Err(Status::permission_denied(
+                "username in metadata does not match authenticated user",
+            ));
+        }
+        request
+            .extensions_mut()
+            .insert(AuthenticatedUser { username });
+        Ok(request)
+    }
+}
+
+/// 签发 HS256 token, 用于运维工具和测试.
+pub fn sign_token(secret: &[u8], username: &str, ttl: Duration) -> Result<String> {
+    let exp = SystemTime::now().duration_since(UNIX_EPOCH)? + ttl;
+    let claims = Claims {
+        sub: username.into(),
+        exp: exp.as_secs(),
+    };
+    let token = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )?;
+    Ok(token)
+}
+
+/// 客户端拦截器, 为每个请求带上 `authorization: Bearer <token>`.
+#[derive(Clone, Default)]
+pub struct BearerToken(Option<MetadataValue<Ascii>>);
+
+impl BearerToken {
+    pub fn new(token: Option<&str>) -> Result<Self> {
+        let value = token
+            .map(|token| format!("Bearer {token}").parse())
+            .transpose()?;
+        Ok(BearerToken(value))
+    }
+}
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.0 {
+            request
+                .metadata_mut()
+                .insert("authorization", value.clone());
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn request(token: Option<&str>, username: Option<&str>) -> Request<()> {
+        let mut request = BearerToken::new(token)
+            .unwrap()
+            .call(Request::new(()))
+            .unwrap();
+        if let Some(username) = username {
+            request
+                .metadata_mut()
+                .insert("username", username.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn valid_token_sets_authenticated_user() {
+        let token = sign_token(SECRET, "alice", Duration::from_secs(60)).unwrap();
+        let request = Authenticator::disabled()
+            .with_hmac_secret(SECRET)
+            .call(request(Some(&token), Some("alice")))
+            .unwrap();
+        assert_eq!(
+            request.extensions().get::<AuthenticatedUser>(),
+            Some(&AuthenticatedUser {
+                username: "alice".into()
+            })
+        );
+    }
+
+    #[test]
+    fn reject_missing_or_invalid_token() {
+        let mut authenticator = Authenticator::disabled().with_hmac_secret(SECRET);
+        let status = authenticator
+            .call(request(None, Some("alice")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let token = sign_token(b"other-secret", "alice", Duration::from_secs(60)).unwrap();
+        let status = authenticator
+            .call(request(Some(&token), Some("alice")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn reject_mismatched_username() {
+        let token = sign_token(SECRET, "alice", Duration::from_secs(60)).unwrap();
+        let status = Authenticator::disabled()
+            .with_hmac_secret(SECRET)
+            .call(request(Some(&token), Some("mallory")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn reject_request_without_client_certificate() {
+        let status = Authenticator::disabled()
+            .with_client_certificate()
+            .call(request(None, Some("alice")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn username_from_certificate_common_name_or_san() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let issue = |common_name: Option<&str>, san: &str| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.distinguished_name = DistinguishedName::new();
+            if let Some(common_name) = common_name {
+                params
+                    .distinguished_name
+                    .push(DnType::CommonName, common_name);
+            }
+            params.signed_by(&key, &ca, &ca_key).unwrap()
+        };
+
+        let cert = issue(Some("alice"), "alice.example.com");
+        assert_eq!(username_from_certificate(cert.der()).unwrap(), "alice");
+
+        let cert = issue(None, "bob");
+        assert_eq!(username_from_certificate(cert.der()).unwrap(), "bob");
+
+        let status = username_from_certificate(b"not a certificate").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn disabled_authenticator_passes_through() {
+        let request = Authenticator::disabled()
+            .call(request(None, Some("alice")))
+            .unwrap();
+        assert!(request.extensions().get::<AuthenticatedUser>().is_none());
+    }
+}
@@ -1,18 +1,28 @@
+use chrono::{DateTime, Local};
 use clap::Parser;
 use crossterm::{
     ExecutableCommand,
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyModifiers},
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, enable_raw_mode},
 };
 use regex::Regex;
-use std::{io, time::Duration};
-use tokio::{sync::mpsc, task};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::mpsc,
+    task,
+};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
 use tonic::{
-    Request,
-    metadata::MetadataValue,
-    transport::{Certificate, Channel, ClientTlsConfig, Uri},
+    Request, Status, Streaming,
+    metadata::{MetadataMap, MetadataValue},
+    service::interceptor::InterceptedService,
+    transport::{Certificate, Channel, ClientTlsConfig, Identity, Uri},
 };
 use tracing::debug;
 use tracing_subscriber::EnvFilter;
@@ -20,11 +30,24 @@ use tui::{
     Frame, Terminal,
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
 };
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use instant_chat::auth::BearerToken;
+use instant_chat::backoff::Backoff;
+use instant_chat::chat_repository::is_after;
+use instant_chat::stub::{
+    ClientMessage, HistoryRequest, ListMembersRequest, ModerationAction, Reaction, ServerMessage,
+    Type, instant_chat_client::InstantChatClient,
+};
+
+type ChatClient = InstantChatClient<InterceptedService<Channel, BearerToken>>;
 
-use instant_chat::stub::{ClientMessage, Type, instant_chat_client::InstantChatClient};
+/// 打开的 Chat 流: 发往服务端的消息和服务端的回复
+type ChatStream = (mpsc::Sender<ClientMessage>, Streaming<ServerMessage>);
 
 /// InstantChat client
 #[derive(Parser, Debug)]
@@ -43,6 +66,18 @@ struct Args {
     #[arg(long, help = "TLS CA file")]
     tls_ca: String,
 
+    /// Client certificate file for mutual TLS, its CN/SAN must be the username
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// Client key file for mutual TLS
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// Bearer token(JWT) issued for the username, required if server enables authentication
+    #[arg(long, env = "INSTANT_CHAT_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
     #[arg(
         long,
         env = "RUST_LOG",
@@ -66,6 +101,23 @@ struct Args {
         help = "Traffic tag list passed to server"
     )]
     traffic_tag: Vec<String>,
+
+    /// Read messages from stdin and print received messages to stdout instead of the terminal UI
+    #[arg(long)]
+    headless: bool,
+
+    /// Output format of headless mode
+    #[arg(long, value_enum, default_value_t = Output::Text, requires = "headless")]
+    output: Output,
+}
+
+/// 无界面模式的输出格式
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Output {
+    /// 每条消息一行文本
+    Text,
+    /// 每条消息一行 JSON
+    Json,
 }
 /// 用户名只能是字母、数字、下划线，3~32 个字符
 fn validate_name(s: &str) -> Result<String, String> {
@@ -77,20 +129,764 @@ fn validate_name(s: &str) -> Result<String, String> {
     }
 }
 
+/// 格式化服务端盖上的消息时间, 以本地时区显示
+fn format_time(at: Option<prost_types::Timestamp>) -> String {
+    at.and_then(|at| DateTime::from_timestamp(at.seconds, at.nanos as u32))
+        .map(|at| at.with_timezone(&Local).format("%H:%M:%S").to_string())
+        .unwrap_or_else(|| Local::now().format("%H:%M:%S").to_string())
+}
+
+/// 解析 `/msg <user> <text>` 私信命令的参数, 返回接收者和内容
+fn parse_direct(arg: &str) -> Option<(&str, &str)> {
+    let (recipient, content) = arg.split_once(' ')?;
+    let content = content.trim();
+    (!content.is_empty()).then_some((recipient, content))
+}
+
+/// 解析 `/edit`, `/delete`, `/react` 参数开头可选的 `#<seq>`, 返回目标消息的 ID 和其余参数.
+/// 没有 `#<seq>` 时选择当前聊天室中最后一条消息, `mine` 为 true 时只选自己的消息
+fn parse_target<'a>(rooms: &Rooms, arg: &'a str, mine: bool) -> Option<(String, &'a str)> {
+    if let Some(rest) = arg.strip_prefix('#') {
+        let (seq, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        let seq: u64 = seq.parse().ok()?;
+        let message = rooms.find_message(|message| message.seq == seq)?;
+        return Some((message.id.clone(), rest.trim()));
+    }
+    let message = rooms.find_message(|message| !mine || message.mine)?;
+    Some((message.id.clone(), arg))
+}
+
+/// 管理事件的提示, 例如 `bob banned alice: spam`
+fn moderation_text(reply: &ServerMessage) -> String {
+    let (actor, target) = (&reply.username, &reply.target_user);
+    let text = match reply.action() {
+        ModerationAction::Kick => format!("{actor} kicked {target}"),
+        ModerationAction::Ban => format!("{actor} banned {target}"),
+        ModerationAction::Unban => format!("{actor} unbanned {target}"),
+        ModerationAction::Mute => format!("{actor} muted {target}"),
+        ModerationAction::Unmute => format!("{actor} unmuted {target}"),
+        ModerationAction::Op => format!("{actor} made {target} a moderator"),
+        ModerationAction::Deop => format!("{actor} removed {target} from moderators"),
+        ModerationAction::Unspecified => format!("{actor} moderated {target}"),
+    };
+    match reply.content.as_str() {
+        "" => text,
+        reason => format!("{text}: {reason}"),
+    }
+}
+
+/// 输入的一行处理后要做的事
+#[derive(Debug, PartialEq)]
+enum CommandAction {
+    /// 已在本地处理, 例如显示了提示
+    Done,
+    Send(ClientMessage),
+    Quit,
+    /// 显示当前聊天室的成员
+    Who,
+    /// 以新的用户名重新连接
+    Nick(String),
+}
+
+/// 斜杠命令, 注册在 [`COMMANDS`] 中
+struct Command {
+    name: &'static str,
+    /// 参数的说明, 显示在帮助和用法中
+    args: &'static str,
+    help: &'static str,
+    handler: fn(&mut Rooms, &Command, &str) -> CommandAction,
+}
+
+impl Command {
+    fn usage(&self) -> String {
+        match self.args {
+            "" => format!("usage: {}", self.name),
+            args => format!("usage: {} {args}", self.name),
+        }
+    }
+}
+
+/// 所有斜杠命令, 按帮助中显示的顺序排列
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "/help",
+        args: "",
+        help: "show this help",
+        handler: help_command,
+    },
+    Command {
+        name: "/quit",
+        args: "",
+        help: "leave all chatrooms and exit",
+        handler: |_, _, _| CommandAction::Quit,
+    },
+    Command {
+        name: "/nick",
+        args: "<name>",
+        help: "reconnect with another username",
+        handler: nick_command,
+    },
+    Command {
+        name: "/me",
+        args: "<action>",
+        help: "send an action, e.g. /me waves",
+        handler: me_command,
+    },
+    Command {
+        name: "/msg",
+        args: "<user> <text>",
+        help: "send a direct message",
+        handler: msg_command,
+    },
+    Command {
+        name: "/who",
+        args: "",
+        help: "list members of the current chatroom",
+        handler: |_, _, _| CommandAction::Who,
+    },
+    Command {
+        name: "/clear",
+        args: "",
+        help: "clear messages of the current chatroom",
+        handler: clear_command,
+    },
+    Command {
+        name: "/join",
+        args: "<room>",
+        help: "join a chatroom and switch to it",
+        handler: join_command,
+    },
+    Command {
+        name: "/leave",
+        args: "[room]",
+        help: "leave a chatroom, the current one by default",
+        handler: leave_command,
+    },
+    Command {
+        name: "/switch",
+        args: "<room>",
+        help: "switch to a joined chatroom",
+        handler: switch_command,
+    },
+    Command {
+        name: "/edit",
+        args: "[#seq] <text>",
+        help: "edit your message, the last one by default",
+        handler: update_command,
+    },
+    Command {
+        name: "/delete",
+        args: "[#seq]",
+        help: "delete your message, the last one by default",
+        handler: update_command,
+    },
+    Command {
+        name: "/react",
+        args: "[#seq] <emoji>",
+        help: "add or remove a reaction, to the last message by default",
+        handler: update_command,
+    },
+    Command {
+        name: "/kick",
+        args: "<user> [reason]",
+        help: "make a user leave the chatroom",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/ban",
+        args: "<user> [reason]",
+        help: "make a user leave the chatroom and never join again",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/unban",
+        args: "<user>",
+        help: "allow a banned user to join again",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/mute",
+        args: "<user> [reason]",
+        help: "stop a user from sending messages",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/unmute",
+        args: "<user>",
+        help: "allow a muted user to send messages again",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/op",
+        args: "<user>",
+        help: "make a user a moderator, owner only",
+        handler: moderation_command,
+    },
+    Command {
+        name: "/deop",
+        args: "<user>",
+        help: "remove a user from moderators, owner only",
+        handler: moderation_command,
+    },
+];
+
+/// 在当前聊天室显示一行提示
+fn notice(rooms: &mut Rooms, text: String) -> CommandAction {
+    rooms.push("", ChatLine::Notice(text));
+    CommandAction::Done
+}
+
+fn help_command(rooms: &mut Rooms, _: &Command, _: &str) -> CommandAction {
+    let width = COMMANDS
+        .iter()
+        .map(|command| command.name.len() + command.args.len() + 1)
+        .max()
+        .unwrap_or_default();
+    for command in COMMANDS {
+        let name = format!("{} {}", command.name, command.args);
+        notice(rooms, format!("{name:width$}  {}", command.help));
+    }
+    notice(
+        rooms,
+        "start a message with // to send it with a leading /".into(),
+    )
+}
+
+fn nick_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    match validate_name(arg) {
+        Ok(username) => CommandAction::Nick(username),
+        Err(_) => notice(rooms, command.usage()),
+    }
+}
+
+fn me_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    if arg.is_empty() {
+        return notice(rooms, command.usage());
+    }
+    chat_message(rooms, format!("{ME_PREFIX}{arg}"))
+}
+
+fn msg_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    let Some((recipient, content)) = parse_direct(arg) else {
+        return notice(rooms, command.usage());
+    };
+    let time = format_time(None);
+    rooms.push(
+        "",
+        ChatLine::Direct(format!("[{time}] You → {recipient}: {content}")),
+    );
+    CommandAction::Send(ClientMessage {
+        r#type: Type::Message.into(),
+        content: content.into(),
+        recipient: recipient.into(),
+        ..Default::default()
+    })
+}
+
+fn clear_command(rooms: &mut Rooms, _: &Command, _: &str) -> CommandAction {
+    rooms.clear();
+    CommandAction::Done
+}
+
+fn join_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    match validate_name(arg) {
+        Ok(room) => CommandAction::Send(ClientMessage {
+            r#type: Type::Connect.into(),
+            room,
+            ..Default::default()
+        }),
+        Err(_) => notice(rooms, command.usage()),
+    }
+}
+
+fn leave_command(rooms: &mut Rooms, _: &Command, arg: &str) -> CommandAction {
+    let room = match arg {
+        "" => rooms.current.clone(),
+        room => room.to_string(),
+    };
+    if !rooms.is_joined(&room) {
+        return notice(rooms, format!("not in chatroom {room}"));
+    }
+    CommandAction::Send(ClientMessage {
+        r#type: Type::Disconnect.into(),
+        room,
+        ..Default::default()
+    })
+}
+
+fn switch_command(rooms: &mut Rooms, _: &Command, arg: &str) -> CommandAction {
+    if !rooms.switch(arg) {
+        return notice(rooms, format!("not in chatroom {arg}, /join {arg} first"));
+    }
+    CommandAction::Done
+}
+
+/// `/edit`, `/delete` 和 `/react`
+fn update_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    let r#type = match command.name {
+        "/edit" => Type::Edit,
+        "/delete" => Type::Delete,
+        _ => Type::Reaction,
+    };
+    let target = parse_target(rooms, arg, r#type != Type::Reaction);
+    let Some((target_id, content)) = target else {
+        return notice(rooms, format!("no such message, {}", command.usage()));
+    };
+    if content.is_empty() != (r#type == Type::Delete) {
+        return notice(rooms, command.usage());
+    }
+    CommandAction::Send(ClientMessage {
+        r#type: r#type.into(),
+        content: content.into(),
+        room: rooms.current.clone(),
+        target_id,
+        ..Default::default()
+    })
+}
+
+/// `/kick`, `/ban`, `/unban`, `/mute`, `/unmute`, `/op` 和 `/deop`
+fn moderation_command(rooms: &mut Rooms, command: &Command, arg: &str) -> CommandAction {
+    let action = match command.name {
+        "/kick" => ModerationAction::Kick,
+        "/ban" => ModerationAction::Ban,
+        "/unban" => ModerationAction::Unban,
+        "/mute" => ModerationAction::Mute,
+        "/unmute" => ModerationAction::Unmute,
+        "/op" => ModerationAction::Op,
+        _ => ModerationAction::Deop,
+    };
+    let (target_user, reason) = arg.split_once(' ').unwrap_or((arg, ""));
+    if target_user.is_empty() || rooms.current.is_empty() {
+        return notice(rooms, command.usage());
+    }
+    CommandAction::Send(ClientMessage {
+        r#type: Type::Moderation.into(),
+        content: reason.trim().into(),
+        room: rooms.current.clone(),
+        action: action.into(),
+        target_user: target_user.into(),
+        ..Default::default()
+    })
+}
+
+/// 发到当前聊天室的聊天消息, 自己的消息等服务端发回后显示, 带上 ID 以便编辑
+fn chat_message(rooms: &mut Rooms, content: String) -> CommandAction {
+    if rooms.current.is_empty() {
+        return notice(rooms, "not in any chatroom, /join <room> first".into());
+    }
+    CommandAction::Send(ClientMessage {
+        r#type: Type::Message.into(),
+        content,
+        room: rooms.current.clone(),
+        ..Default::default()
+    })
+}
+
+/// 处理输入的一行. 以 `/` 开头的是 [`COMMANDS`] 中的命令, 未知的命令显示帮助而不发送;
+/// 以 `//` 开头的去掉一个 `/` 后作为聊天消息, 其余作为聊天消息发到当前聊天室
+fn handle_input(rooms: &mut Rooms, input: &str) -> CommandAction {
+    if input.trim().is_empty() {
+        return CommandAction::Done;
+    }
+    if let Some(content) = input.strip_prefix('/')
+        && !content.starts_with('/')
+    {
+        let (name, arg) = input
+            .split_once(' ')
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((input, ""));
+        return match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.handler)(rooms, command, arg),
+            None => {
+                notice(rooms, format!("unknown command {name}, commands are:"));
+                help_command(rooms, &COMMANDS[0], "")
+            }
+        };
+    }
+    let content = input.strip_prefix('/').unwrap_or(input);
+    chat_message(rooms, content.into())
+}
+
+/// 重新连接时要重新加入的聊天室, 除了 Chat 流 metadata 中的 `chatroom`
+fn rejoin_messages(rooms: &Rooms, chatroom: &str) -> Vec<ClientMessage> {
+    rooms
+        .joined
+        .iter()
+        .filter(|room| *room != chatroom)
+        .map(|room| ClientMessage {
+            r#type: Type::Connect.into(),
+            room: room.clone(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// 带上 `metadata` 中用户名等的请求, 服务端据此检查用户是否被聊天室封禁
+fn with_metadata<T>(message: T, metadata: &MetadataMap) -> Request<T> {
+    let mut request = Request::new(message);
+    *request.metadata_mut() = metadata.clone();
+    request
+}
+
+/// 在后台拉取聊天室成员列表, 结果通过 members_tx 送回 UI
+fn refresh_members(
+    client: &ChatClient,
+    metadata: &MetadataMap,
+    chatroom: &str,
+    members_tx: &mpsc::Sender<Vec<String>>,
+) {
+    let mut client = client.clone();
+    let request = with_metadata(
+        ListMembersRequest {
+            chatroom: chatroom.into(),
+        },
+        metadata,
+    );
+    let members_tx = members_tx.clone();
+    task::spawn(async move {
+        match client.list_members(request).await {
+            Ok(response) => {
+                members_tx.send(response.into_inner().usernames).await.ok();
+            }
+            Err(status) => debug!(?status, "failed to list members"),
+        }
+    });
+}
+
+/// 打开 Chat 流, `metadata` 中已有用户名等, 再加上要加入的聊天室
+async fn open_chat(
+    client: &mut ChatClient,
+    metadata: &MetadataMap,
+    chatroom: &str,
+) -> Result<ChatStream, Status> {
+    let (to_server_tx, to_server_rx) = mpsc::channel::<ClientMessage>(32);
+    let mut chat_request = with_metadata(ReceiverStream::new(to_server_rx), metadata);
+    let chatroom = MetadataValue::try_from(chatroom)
+        .map_err(|_| Status::invalid_argument("invalid chatroom"))?;
+    chat_request.metadata_mut().insert("chatroom", chatroom);
+    let response_stream = client.chat(chat_request).await?.into_inner();
+    Ok((to_server_tx, response_stream))
+}
+
+/// 无界面模式中消息的一行文本, 正在输入和已读回执不输出
+fn headless_text(reply: &ServerMessage) -> Option<String> {
+    let time = format_time(reply.at);
+    let prefix = match reply.room.as_str() {
+        "" => format!("[{time}]"),
+        room => format!("[{time}] {room}"),
+    };
+    let (username, content) = (&reply.username, &reply.content);
+    let text = match reply.r#type() {
+        Type::Typing | Type::Read => return None,
+        Type::Connect => format!("{prefix} * {username} joined"),
+        Type::Disconnect => format!("{prefix} * {username} left"),
+        Type::Error | Type::Shutdown => format!("{prefix} ! {content}"),
+        Type::Direct => format!("{prefix} {username} → {}: {content}", reply.recipient),
+        Type::Edit => format!("{prefix} {username} edited {}: {content}", reply.target_id),
+        Type::Delete => format!("{prefix} {username} deleted {}", reply.target_id),
+        Type::Reaction => format!(
+            "{prefix} {username} reacted {content} to {}",
+            reply.target_id
+        ),
+        Type::Moderation => format!("{prefix} * {}", moderation_text(reply)),
+        _ => match content.strip_prefix(ME_PREFIX) {
+            Some(action) => format!("{prefix} #{} * {username} {action}", reply.seq),
+            None => format!("{prefix} #{} {username}: {content}", reply.seq),
+        },
+    };
+    Some(text)
+}
+
+/// 无界面模式中消息的一行 JSON, 字段与 `ServerMessage` 一致, 类型和管理操作为小写名称
+fn headless_json(reply: &ServerMessage) -> serde_json::Value {
+    let name = |name: &str, prefix: &str| name.trim_start_matches(prefix).to_lowercase();
+    let at = reply
+        .at
+        .and_then(|at| DateTime::from_timestamp(at.seconds, at.nanos as u32))
+        .map(|at| at.to_rfc3339());
+    let reactions: Vec<_> = reply
+        .reactions
+        .iter()
+        .map(|reaction| serde_json::json!({"emoji": reaction.emoji, "usernames": reaction.usernames}))
+        .collect();
+    serde_json::json!({
+        "type": name(reply.r#type().as_str_name(), "TYPE_"),
+        "room": reply.room,
+        "username": reply.username,
+        "content": reply.content,
+        "id": reply.id,
+        "seq": reply.seq,
+        "recipient": reply.recipient,
+        "target_id": reply.target_id,
+        "edited": reply.edited,
+        "deleted": reply.deleted,
+        "reactions": reactions,
+        "action": name(reply.action().as_str_name(), "MODERATION_ACTION_"),
+        "target_user": reply.target_user,
+        "at": at,
+    })
+}
+
+/// 无界面模式: 把标准输入的每一行作为消息发到聊天室, 把收到的消息输出到标准输出.
+/// 加入前先读取聊天室最后一条消息的 ID, 回放的历史都不晚于它, 之后自己的加入事件才是这次的.
+/// 收到这次的加入事件后才读取标准输入, 只把它之后自己的消息当作发回的消息.
+/// 标准输入结束后等服务端发回已发送的消息再关闭 Chat 流, 服务端关闭 Chat 流后退出
+async fn run_headless(
+    client: &mut ChatClient,
+    metadata: &MetadataMap,
+    username: &str,
+    chatroom: &str,
+    output: Output,
+) -> anyhow::Result<()> {
+    let latest = HistoryRequest {
+        chatroom: chatroom.into(),
+        limit: 1,
+        since_id: String::new(),
+    };
+    let replayed_until = client
+        .history(with_metadata(latest, metadata))
+        .await?
+        .into_inner()
+        .messages
+        .pop()
+        .map(|message| message.id)
+        .unwrap_or_default();
+    let (to_server_tx, mut response_stream) = open_chat(client, metadata, chatroom).await?;
+    let mut to_server_tx = Some(to_server_tx);
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    // 这次加入事件的 ID, 收到之前为 None
+    let mut joined_id: Option<String> = None;
+    let mut stdin_open = true;
+    // 已发送但服务端还没有发回的消息数
+    let mut unacked = 0usize;
+    let drain_deadline = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(drain_deadline);
+    let mut stdout = tokio::io::stdout();
+
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if joined_id.is_some() && stdin_open => {
+                match line? {
+                    Some(line) if line.trim().is_empty() => {},
+                    Some(line) => {
+                        let request = ClientMessage {
+                            r#type: Type::Message.into(),
+                            content: line,
+                            room: chatroom.into(),
+                            ..Default::default()
+                        };
+                        if let Some(tx) = &to_server_tx && tx.send(request).await.is_ok() {
+                            unacked += 1;
+                        }
+                    },
+                    None => {
+                        stdin_open = false;
+                        drain_deadline.as_mut().reset(tokio::time::Instant::now() + HEADLESS_DRAIN_TIMEOUT);
+                    },
+                }
+            },
+            reply = response_stream.message() => {
+                let Some(reply) = reply? else {
+                    return Ok(());
+                };
+                let is_self = reply.username == username && reply.room == chatroom;
+                let acked = match (reply.r#type(), &joined_id) {
+                    (Type::Connect, None) if is_self && is_after(&reply.id, &replayed_until) => {
+                        joined_id = Some(reply.id.clone());
+                        false
+                    },
+                    (Type::Message, Some(joined_id)) => is_self && is_after(&reply.id, joined_id),
+                    (Type::Error, Some(_)) => true,
+                    _ => false,
+                };
+                if acked {
+                    unacked = unacked.saturating_sub(1);
+                }
+                let line = match output {
+                    Output::Json => Some(headless_json(&reply).to_string()),
+                    Output::Text => headless_text(&reply),
+                };
+                if let Some(line) = line {
+                    stdout.write_all(format!("{line}\n").as_bytes()).await?;
+                    stdout.flush().await?;
+                }
+            },
+            _ = &mut drain_deadline, if to_server_tx.is_some() => {
+                unacked = 0;
+            },
+        }
+        // 关闭发送端后服务端结束 Chat 流
+        if !stdin_open && unacked == 0 {
+            to_server_tx = None;
+        }
+    }
+}
+
+/// 读取下一条回复, 先返回重连后补齐的消息, 断线期间一直等待
+async fn next_reply(
+    missed: &mut VecDeque<ServerMessage>,
+    response_stream: &mut Option<Streaming<ServerMessage>>,
+) -> Result<Option<ServerMessage>, Status> {
+    if let Some(reply) = missed.pop_front() {
+        return Ok(Some(reply));
+    }
+    match response_stream {
+        Some(response_stream) => response_stream.message().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 已经重连了 `elapsed` 时仍失败时是否继续重试, 被拒绝的连接重试也不会成功. 服务端拒绝
+/// 重复登录时返回的 `AlreadyExists` 可能来自断线前自己的连接, 它的在线状态过期后即可重连,
+/// 因此在 [`DUPLICATE_LOGIN_RETRY`] 内继续重试, 之后认为用户名被另一个连接占用
+fn is_retryable(status: &Status, elapsed: Duration) -> bool {
+    match status.code() {
+        tonic::Code::AlreadyExists => elapsed < DUPLICATE_LOGIN_RETRY,
+        code => !matches!(
+            code,
+            tonic::Code::InvalidArgument
+                | tonic::Code::PermissionDenied
+                | tonic::Code::Unauthenticated
+                | tonic::Code::FailedPrecondition
+        ),
+    }
+}
+
+/// 断线后在后台以指数退避重新打开 Chat 流
+struct Reconnector {
+    client: ChatClient,
+    metadata: MetadataMap,
+    /// 重连成功后送回新的 Chat 流和断线期间错过的消息, 遇到不可重试的错误时送回错误
+    connected_tx: mpsc::Sender<Result<(ChatStream, Vec<ServerMessage>), Status>>,
+    quit_token: CancellationToken,
+}
+
+impl Reconnector {
+    /// 重新加入 `chatroom`, 之后从历史中读取各聊天室在 `last_ids` 之后错过的消息.
+    /// 先打开 Chat 流再读历史, 两者重叠的消息由 [`Rooms::record`] 去重
+    fn start(&self, chatroom: String, last_ids: HashMap<String, String>) {
+        let mut client = self.client.clone();
+        let metadata = self.metadata.clone();
+        let connected_tx = self.connected_tx.clone();
+        let quit_token = self.quit_token.clone();
+        task::spawn(async move {
+            let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+            let started = Instant::now();
+            let chat_stream = loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff.next_delay()) => {},
+                    _ = quit_token.cancelled() => return,
+                }
+                match open_chat(&mut client, &metadata, &chatroom).await {
+                    Ok(chat_stream) => break chat_stream,
+                    Err(status) if is_retryable(&status, started.elapsed()) => {
+                        debug!(?status, "failed to reconnect")
+                    }
+                    Err(status) => {
+                        connected_tx.send(Err(status)).await.ok();
+                        return;
+                    }
+                }
+            };
+            let mut missed = vec![];
+            for (room, since_id) in last_ids {
+                let request = HistoryRequest {
+                    chatroom: room,
+                    limit: RESUME_HISTORY_LIMIT,
+                    since_id,
+                };
+                match client.history(with_metadata(request, &metadata)).await {
+                    Ok(response) => missed.extend(response.into_inner().messages),
+                    Err(status) => debug!(?status, "failed to read missed messages"),
+                }
+            }
+            connected_tx.send(Ok((chat_stream, missed))).await.ok();
+        });
+    }
+}
+
+/// 发往服务端的消息. 断线期间先放到发件箱, 重连后按顺序发送
+struct Outbox {
+    /// 断线期间为 None
+    to_server_tx: Option<mpsc::Sender<ClientMessage>>,
+    pending: VecDeque<ClientMessage>,
+}
+
+impl Outbox {
+    fn new(to_server_tx: mpsc::Sender<ClientMessage>) -> Self {
+        Outbox {
+            to_server_tx: Some(to_server_tx),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn is_online(&self) -> bool {
+        self.to_server_tx.is_some()
+    }
+
+    /// 发送消息, 断线期间放到发件箱. 输入状态和已读回执过时无用, 断线期间直接丢弃
+    async fn send(&mut self, message: ClientMessage) {
+        let message = match &self.to_server_tx {
+            Some(to_server_tx) => match to_server_tx.send(message).await {
+                Ok(()) => return,
+                Err(err) => err.0,
+            },
+            None => message,
+        };
+        if !matches!(message.r#type(), Type::Typing | Type::Read) {
+            self.pending.push_back(message);
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.to_server_tx = None;
+    }
+
+    /// 重连后先发送 `first`(重新加入聊天室), 再按顺序发送发件箱中的消息
+    async fn connected(
+        &mut self,
+        to_server_tx: mpsc::Sender<ClientMessage>,
+        first: Vec<ClientMessage>,
+    ) {
+        self.to_server_tx = Some(to_server_tx);
+        let pending = std::mem::take(&mut self.pending);
+        for message in first.into_iter().chain(pending) {
+            self.send(message).await;
+        }
+    }
+
+    /// 断线期间显示在标题中的提示
+    fn banner(&self) -> Option<String> {
+        match (self.is_online(), self.pending.len()) {
+            (true, _) => None,
+            (false, 0) => Some("reconnecting...".into()),
+            (false, queued) => Some(format!("reconnecting... ({queued} queued)")),
+        }
+    }
+}
+
+/// 断线重连时加入的聊天室, 其他已加入的聊天室重连后再加入
+fn reconnect_room<'a>(rooms: &'a Rooms, chatroom: &'a str) -> &'a str {
+    rooms.joined.first().map_or(chatroom, String::as_str)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let addr: Uri = args.addr.parse()?;
     let env_filter = EnvFilter::new(args.log_level);
 
+    // 日志输出到标准错误, 不与无界面模式的输出混在一起
     if args.log_json {
         tracing_subscriber::fmt()
             .json()
+            .with_writer(io::stderr)
             .with_env_filter(env_filter)
             .with_target(true)
             .init();
     } else {
         tracing_subscriber::fmt()
+            .with_writer(io::stderr)
             .with_env_filter(env_filter)
             .with_target(true)
             .init();
@@ -102,28 +898,56 @@ async fn main() -> anyhow::Result<()> {
         .map_err(|err| anyhow::format_err!("{err}"))?;
     let ca_cert = tokio::fs::read(args.tls_ca).await?;
     let ca = Certificate::from_pem(ca_cert);
-    let tls = ClientTlsConfig::new()
+    let mut tls = ClientTlsConfig::new()
         .ca_certificate(ca)
         .domain_name(domain);
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let cert = tokio::fs::read(cert).await?;
+        let key = tokio::fs::read(key).await?;
+        tls = tls.identity(Identity::from_pem(cert, key));
+    }
 
     let channel = Channel::builder(addr).tls_config(tls)?.connect().await?;
-    let mut client = InstantChatClient::new(channel);
+    let mut client =
+        InstantChatClient::with_interceptor(channel, BearerToken::new(args.token.as_deref())?);
 
-    let (to_server_tx, to_server_rx) = mpsc::channel::<ClientMessage>(32);
-    let outbound = ReceiverStream::new(to_server_rx);
-    let mut chat_request = Request::new(outbound);
-    let metadata = chat_request.metadata_mut();
+    let mut metadata = MetadataMap::new();
     metadata.insert("username", MetadataValue::try_from(&args.username)?);
-    metadata.insert("chatroom", MetadataValue::try_from(&args.chatroom)?);
     for tag in args.traffic_tag.iter() {
         metadata.append("x-traffic-tag", MetadataValue::try_from(tag)?);
     }
+    if args.headless {
+        return run_headless(
+            &mut client,
+            &metadata,
+            &args.username,
+            &args.chatroom,
+            args.output,
+        )
+        .await;
+    }
+
     debug!(args.username, args.chatroom, "starting chat");
-    let mut response_stream = client.chat(chat_request).await?.into_inner();
+    let (to_server_tx, response_stream) = open_chat(&mut client, &metadata, &args.chatroom).await?;
     debug!(args.username, args.chatroom, "chat started");
+    // 断线期间为 None
+    let mut response_stream = Some(response_stream);
+    let mut outbox = Outbox::new(to_server_tx);
+    // 重连后补齐的消息, 先于新的 Chat 流中的消息处理
+    let mut missed = VecDeque::new();
+
+    let (members_tx, mut members_rx) = mpsc::channel::<Vec<String>>(4);
+    refresh_members(&client, &metadata, &args.chatroom, &members_tx);
 
     let (ui_tx, mut ui_rx) = mpsc::channel::<UiEvent>(32);
     let quit_token = CancellationToken::new();
+    let (connected_tx, mut connected_rx) = mpsc::channel(1);
+    let mut reconnector = Reconnector {
+        client: client.clone(),
+        metadata,
+        connected_tx,
+        quit_token: quit_token.clone(),
+    };
 
     // 输入任务
     {
@@ -132,22 +956,38 @@ async fn main() -> anyhow::Result<()> {
             loop {
                 if event::poll(Duration::from_millis(100)).unwrap() {
                     match event::read().unwrap() {
-                        Event::Key(key) => match key.code {
-                            KeyCode::Enter => {
-                                ui_tx.send(UiEvent::Enter).await.ok();
-                            }
-                            KeyCode::Char(c) => {
-                                ui_tx.send(UiEvent::Char(c)).await.ok();
-                            }
-                            KeyCode::Backspace => {
-                                ui_tx.send(UiEvent::Backspace).await.ok();
-                            }
-                            KeyCode::Esc => {
-                                quit_token.cancel();
-                                break;
-                            }
-                            _ => {}
-                        },
+                        Event::Key(key) => {
+                            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                            let alt = key.modifiers.contains(KeyModifiers::ALT);
+                            let ui_event = match key.code {
+                                KeyCode::Enter => UiEvent::Enter,
+                                KeyCode::Char('w') if ctrl => UiEvent::Edit(EditKey::DeleteWord),
+                                KeyCode::Char('a') if ctrl => UiEvent::Edit(EditKey::Home),
+                                KeyCode::Char('e') if ctrl => UiEvent::Edit(EditKey::End),
+                                KeyCode::Char(c) => UiEvent::Char(c),
+                                KeyCode::Backspace if ctrl || alt => {
+                                    UiEvent::Edit(EditKey::DeleteWord)
+                                }
+                                KeyCode::Backspace => UiEvent::Edit(EditKey::Backspace),
+                                KeyCode::Delete => UiEvent::Edit(EditKey::Delete),
+                                KeyCode::Left if ctrl || alt => UiEvent::Edit(EditKey::WordLeft),
+                                KeyCode::Left => UiEvent::Edit(EditKey::Left),
+                                KeyCode::Right if ctrl || alt => UiEvent::Edit(EditKey::WordRight),
+                                KeyCode::Right => UiEvent::Edit(EditKey::Right),
+                                KeyCode::Home => UiEvent::Edit(EditKey::Home),
+                                KeyCode::End => UiEvent::Edit(EditKey::End),
+                                KeyCode::Up => UiEvent::Edit(EditKey::HistoryPrev),
+                                KeyCode::Down => UiEvent::Edit(EditKey::HistoryNext),
+                                KeyCode::PageUp => UiEvent::PageUp,
+                                KeyCode::PageDown => UiEvent::PageDown,
+                                KeyCode::Esc => {
+                                    quit_token.cancel();
+                                    break;
+                                }
+                                _ => continue,
+                            };
+                            ui_tx.send(ui_event).await.ok();
+                        }
                         Event::Resize(_, _) => {
                             ui_tx.send(UiEvent::Resize).await.ok();
                         }
@@ -161,73 +1001,759 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
-    let mut ui = Ui::new(&args.username, &args.chatroom)?;
-    let mut messages = vec![];
-    let mut input_buffer = String::new();
-    ui.draw(&messages, &input_buffer)?;
+    let mut username = args.username.clone();
+    let mut ui = Ui::new(&username)?;
+    let mut rooms = Rooms::new(&args.chatroom);
+    let mut members = vec![];
+    let mut editor = LineEditor::default();
+    let mut exit_reason = None;
+    let mut typing_sent_at: Option<Instant> = None;
+    // 定时重绘以清除过期的输入状态, 同时发送已读回执
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    ui.draw(&rooms, &members, &editor)?;
     loop {
         tokio::select! {
-            reply = response_stream.message() => {
+            reply = next_reply(&mut missed, &mut response_stream) => {
                 match reply {
-                    Ok(None) => {
+                    // 被服务端断开时不再重连
+                    Ok(None) if exit_reason.is_some() => {
                         quit_token.cancel();
                     },
                     Ok(Some(reply)) => {
-                        if !reply.username.eq(&args.username) {
-                            messages.push(format!("{}: {}", reply.username, reply.content));
+                        let time = format_time(reply.at);
+                        let room = reply.room.clone();
+                        let is_self = reply.username == username;
+                        // 重连后服务端回放的消息可能已经收到过
+                        let replayed = !rooms.record(&room, &reply.id);
+                        match reply.r#type() {
+                            _ if replayed => {},
+                            // 加入新的聊天室后切换过去
+                            Type::Connect if is_self && rooms.join(&room) => {
+                                rooms.push(&room, ChatLine::Notice(format!("[{time}] * joined {room}")));
+                                refresh_members(&client, &reconnector.metadata, &room, &members_tx);
+                            },
+                            Type::Connect => {
+                                rooms.push(&room, ChatLine::Notice(format!(
+                                    "[{time}] * {} joined", reply.username
+                                )));
+                                if room == rooms.current {
+                                    refresh_members(&client, &reconnector.metadata, &room, &members_tx);
+                                }
+                            },
+                            // 离开聊天室, 服务端断开本连接时 content 为原因, 例如在其他地方登录
+                            Type::Disconnect if is_self => {
+                                if rooms.leave(&room) {
+                                    let notice = match reply.content.as_str() {
+                                        "" => format!("[{time}] * left {room}"),
+                                        reason => format!("[{time}] * disconnected from {room}: {reason}"),
+                                    };
+                                    rooms.push("", ChatLine::Notice(notice));
+                                    refresh_members(&client, &reconnector.metadata, &rooms.current, &members_tx);
+                                }
+                                if !reply.content.is_empty() && rooms.joined.is_empty() {
+                                    exit_reason = Some(format!("disconnected by server: {}", reply.content));
+                                }
+                            },
+                            Type::Disconnect => {
+                                rooms.stop_typing(&room, &reply.username);
+                                rooms.push(&room, ChatLine::Notice(format!(
+                                    "[{time}] * {} left", reply.username
+                                )));
+                                if room == rooms.current {
+                                    refresh_members(&client, &reconnector.metadata, &room, &members_tx);
+                                }
+                            },
+                            // 服务端随后断开, 之后重连
+                            Type::Shutdown => rooms.push("", ChatLine::Notice(format!(
+                                "[{time}] ! {}", reply.content
+                            ))),
+                            Type::Error => rooms.push(&room, ChatLine::Notice(format!(
+                                "[{time}] ! {}", reply.content
+                            ))),
+                            Type::Direct => rooms.push("", ChatLine::Direct(format!(
+                                "[{time}] {} → you: {}", reply.username, reply.content
+                            ))),
+                            Type::Edit | Type::Delete | Type::Reaction => rooms.update(&room, &reply),
+                            Type::Typing | Type::Read if is_self => {},
+                            Type::Typing => rooms.typing(&room, &reply.username),
+                            Type::Read => rooms.read(&room, &reply.username, &reply.target_id),
+                            // 自己被踢出或封禁时随后会收到断开事件
+                            Type::Moderation => {
+                                rooms.push(&room, ChatLine::Notice(format!(
+                                    "[{time}] * {}", moderation_text(&reply)
+                                )));
+                                if room == rooms.current {
+                                    refresh_members(&client, &reconnector.metadata, &room, &members_tx);
+                                }
+                            },
+                            _ => {
+                                rooms.stop_typing(&room, &reply.username);
+                                rooms.push(&room, ChatLine::Message(ChatMessage::new(&reply, time, is_self)));
+                            },
                         }
                     },
-                    Err(status) => messages.push(format!("(Server): {status}")),
+                    // 连接断开或服务端关闭, 在后台重连
+                    disconnected => {
+                        let reason = match disconnected {
+                            Err(status) => status.message().to_string(),
+                            _ => "connection closed".into(),
+                        };
+                        rooms.push("", ChatLine::Notice(format!(
+                            "[{}] ! {reason}, reconnecting", format_time(None)
+                        )));
+                        response_stream = None;
+                        outbox.disconnected();
+                        let chatroom = reconnect_room(&rooms, &args.chatroom).to_string();
+                        reconnector.start(chatroom, rooms.last_ids.clone());
+                    },
                 };
             },
+            Some(connected) = connected_rx.recv() => {
+                match connected {
+                    Ok(((to_server_tx, stream), messages)) => {
+                        response_stream = Some(stream);
+                        missed.extend(messages);
+                        let rejoin = rejoin_messages(&rooms, reconnect_room(&rooms, &args.chatroom));
+                        outbox.connected(to_server_tx, rejoin).await;
+                        rooms.push("", ChatLine::Notice(format!("[{}] * reconnected", format_time(None))));
+                        refresh_members(&client, &reconnector.metadata, &rooms.current, &members_tx);
+                    },
+                    Err(status) => {
+                        exit_reason = Some(format!("failed to reconnect: {}", status.message()));
+                        quit_token.cancel();
+                    },
+                }
+            },
+            Some(usernames) = members_rx.recv() => {
+                members = usernames;
+            },
             Some(ui_event) = ui_rx.recv() => {
                 match ui_event {
                     UiEvent::Enter => {
-                        if !input_buffer.trim().is_empty() {
-                            messages.push(format!("You: {input_buffer}"));
-                            let chat_request = ClientMessage {
-                                r#type: Type::Message.into(),
-                                content: input_buffer.clone(),
-                                at: None,
-                            };
-                            to_server_tx.send(chat_request).await.ok();
-                            input_buffer.clear();
+                        let input = editor.submit();
+                        let previous = rooms.current.clone();
+                        match handle_input(&mut rooms, &input) {
+                            CommandAction::Done => {},
+                            CommandAction::Send(request) => outbox.send(request).await,
+                            CommandAction::Quit => quit_token.cancel(),
+                            CommandAction::Who => {
+                                let notice = format!("members of {}: {}", rooms.current, members.join(", "));
+                                rooms.push("", ChatLine::Notice(notice));
+                            },
+                            // 先以新的用户名连接, 成功后再断开旧的连接, 失败时保留旧的连接
+                            CommandAction::Nick(nick) if outbox.is_online() => {
+                                let mut metadata = reconnector.metadata.clone();
+                                metadata.insert("username", MetadataValue::try_from(&nick)?);
+                                let chatroom = reconnect_room(&rooms, &args.chatroom);
+                                match open_chat(&mut client, &metadata, chatroom).await {
+                                    Ok((to_server_tx, stream)) => {
+                                        response_stream = Some(stream);
+                                        let rejoin = rejoin_messages(&rooms, chatroom);
+                                        outbox.connected(to_server_tx, rejoin).await;
+                                        reconnector.metadata = metadata;
+                                        ui.username = nick.clone();
+                                        rooms.push("", ChatLine::Notice(format!("[{}] * you are now known as {nick}", format_time(None))));
+                                        username = nick;
+                                    },
+                                    Err(status) => rooms.push("", ChatLine::Notice(format!(
+                                        "[{}] ! failed to change username: {}", format_time(None), status.message()
+                                    ))),
+                                }
+                            },
+                            CommandAction::Nick(_) => rooms.push("", ChatLine::Notice(
+                                "! not connected, try again later".into()
+                            )),
+                        }
+                        if rooms.current != previous {
+                            refresh_members(&client, &reconnector.metadata, &rooms.current, &members_tx);
                         }
                     },
-                    UiEvent::Backspace => { input_buffer.pop(); },
+                    UiEvent::Edit(key) => editor.edit(key),
+                    UiEvent::PageUp => ui.page_up(&rooms),
+                    UiEvent::PageDown => ui.page_down(&rooms),
                     // leave it to redraw
                     UiEvent::Resize => { },
-                    UiEvent::Char(c) => input_buffer.push(c),
+                    UiEvent::Char(c) => {
+                        editor.insert(c);
+                        // 输入聊天消息时每隔几秒告诉其他人正在输入, 命令不算
+                        let resend = typing_sent_at.is_none_or(|at| at.elapsed() >= TYPING_RESEND);
+                        if resend && !editor.text().starts_with('/') && !rooms.current.is_empty() {
+                            typing_sent_at = Some(Instant::now());
+                            let typing = ClientMessage {
+                                r#type: Type::Typing.into(),
+                                room: rooms.current.clone(),
+                                ..Default::default()
+                            };
+                            outbox.send(typing).await;
+                        }
+                    },
+                }
+            },
+            _ = tick.tick() => {
+                if let Some(read) = rooms.take_unread() {
+                    outbox.send(read).await;
                 }
             },
             _ = quit_token.cancelled() => {
                 // send close to server before exit
-                drop(to_server_tx);
+                drop(outbox);
                 break;
             },
         }
-        ui.draw(&messages, &input_buffer)?;
+        ui.banner = outbox.banner();
+        ui.draw(&rooms, &members, &editor)?;
     }
 
-    ui.cleanup()
+    ui.cleanup()?;
+    if let Some(reason) = exit_reason {
+        eprintln!("{reason}");
+    }
+    Ok(())
+}
+
+/// 其他用户的输入状态在没有新的输入事件后多久过期. 服务端不保存输入状态, 由客户端按
+/// instant_chat.proto 中 `TYPE_TYPING` 的约定过期
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 输入时重复发送输入状态的间隔
+const TYPING_RESEND: Duration = Duration::from_secs(2);
+
+/// 断线后第一次重连前的等待时间, 之后每次失败翻倍
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// 重连的最长等待时间
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// 重连被拒绝为重复登录时继续重试的时长, 覆盖断线前的连接在服务端的在线状态过期的时间
+/// (服务端 `--presence-ttl-secs` 默认 30 秒)
+const DUPLICATE_LOGIN_RETRY: Duration = Duration::from_secs(60);
+
+/// 重连后每个聊天室最多补齐的消息数量, 服务端还会限制在历史保留的数量以内
+const RESUME_HISTORY_LIMIT: u32 = 500;
+
+/// 无界面模式中标准输入结束后, 最多等待服务端发回已发送消息的时间
+const HEADLESS_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `/me` 发送的动作消息内容的前缀, 显示为 `* <username> <action>`
+const ME_PREFIX: &str = "/me ";
+
+/// 最多保留的输入历史数量
+const INPUT_HISTORY_LEN: usize = 100;
+
+/// 成员列表的宽度, 过长的用户名会被截断
+const MEMBER_LIST_WIDTH: u16 = 24;
+
+/// 已加入的聊天室和各自的消息, 消息列表只显示当前聊天室
+pub struct Rooms {
+    /// 按加入顺序排列
+    joined: Vec<String>,
+    /// 当前聊天室, 没有加入任何聊天室时为空
+    current: String,
+    lines: HashMap<String, Vec<ChatLine>>,
+    /// 每个聊天室中正在输入的用户及其过期时间
+    typing: HashMap<String, HashMap<String, Instant>>,
+    /// 每个聊天室已发送已读回执的最后一条消息
+    read: HashMap<String, String>,
+    /// 每个聊天室收到的最后一条消息的 ID, 重连后从这里继续
+    last_ids: HashMap<String, String>,
+}
+
+impl Rooms {
+    fn new(room: &str) -> Self {
+        Rooms {
+            joined: vec![room.into()],
+            current: room.into(),
+            lines: HashMap::new(),
+            typing: HashMap::new(),
+            read: HashMap::new(),
+            last_ids: HashMap::new(),
+        }
+    }
+
+    fn is_joined(&self, room: &str) -> bool {
+        self.joined.iter().any(|joined| joined == room)
+    }
+
+    /// 加入聊天室并切换过去, 已加入时返回 false
+    fn join(&mut self, room: &str) -> bool {
+        if self.is_joined(room) {
+            return false;
+        }
+        self.joined.push(room.into());
+        self.current = room.into();
+        true
+    }
+
+    /// 离开聊天室, 离开当前聊天室后切换到第一个仍加入的聊天室. 未加入时返回 false
+    fn leave(&mut self, room: &str) -> bool {
+        let Some(index) = self.joined.iter().position(|joined| joined == room) else {
+            return false;
+        };
+        self.joined.remove(index);
+        self.lines.remove(room);
+        self.last_ids.remove(room);
+        if self.current == room {
+            self.current = self.joined.first().cloned().unwrap_or_default();
+        }
+        true
+    }
+
+    fn switch(&mut self, room: &str) -> bool {
+        if !self.is_joined(room) {
+            return false;
+        }
+        self.current = room.into();
+        true
+    }
+
+    /// 记录收到的聊天室消息, 已经收到过时返回 false. 没有 ID 的消息不在历史中, 总是返回 true
+    fn record(&mut self, room: &str, id: &str) -> bool {
+        if id.is_empty() {
+            return true;
+        }
+        if self
+            .last_ids
+            .get(room)
+            .is_some_and(|last| !is_after(id, last))
+        {
+            return false;
+        }
+        self.last_ids.insert(room.into(), id.into());
+        true
+    }
+
+    /// 添加一行到聊天室的消息列表, 私信和提示等不属于已加入聊天室的显示在当前聊天室
+    fn push(&mut self, room: &str, line: ChatLine) {
+        let room = match self.is_joined(room) {
+            true => room,
+            false => &self.current,
+        };
+        self.lines.entry(room.into()).or_default().push(line);
+    }
+
+    /// 清空当前聊天室显示的消息, 已收到的消息 ID 保留, 不会重复显示
+    fn clear(&mut self) {
+        self.lines.remove(&self.current);
+    }
+
+    fn typing(&mut self, room: &str, username: &str) {
+        let expires_at = Instant::now() + TYPING_TIMEOUT;
+        self.typing
+            .entry(room.into())
+            .or_default()
+            .insert(username.into(), expires_at);
+    }
+
+    fn stop_typing(&mut self, room: &str, username: &str) {
+        if let Some(typing) = self.typing.get_mut(room) {
+            typing.remove(username);
+        }
+    }
+
+    /// 当前聊天室中正在输入的提示, 没有人输入时为空
+    fn typing_line(&self) -> String {
+        let now = Instant::now();
+        let mut usernames: Vec<&str> = self
+            .typing
+            .get(&self.current)
+            .into_iter()
+            .flatten()
+            .filter(|(_, expires_at)| **expires_at > now)
+            .map(|(username, _)| username.as_str())
+            .collect();
+        usernames.sort();
+        match usernames.as_slice() {
+            [] => String::new(),
+            [username] => format!("{username} is typing..."),
+            usernames => format!("{} are typing...", usernames.join(", ")),
+        }
+    }
+
+    /// 其他用户已读到 `target_id`, 已读标记从之前的消息移到该消息上
+    fn read(&mut self, room: &str, username: &str, target_id: &str) {
+        let Some(lines) = self.lines.get_mut(room) else {
+            return;
+        };
+        for line in lines.iter_mut() {
+            if let ChatLine::Message(message) = line {
+                message.seen_by.retain(|seen| seen != username);
+                if message.id == target_id {
+                    message.seen_by.push(username.into());
+                }
+            }
+        }
+    }
+
+    /// 当前聊天室中有新消息显示后, 返回要发送的已读回执
+    fn take_unread(&mut self) -> Option<ClientMessage> {
+        let last_id = self.find_message(|_| true)?.id.clone();
+        if self.read.get(&self.current) == Some(&last_id) {
+            return None;
+        }
+        self.read.insert(self.current.clone(), last_id.clone());
+        Some(ClientMessage {
+            r#type: Type::Read.into(),
+            room: self.current.clone(),
+            target_id: last_id,
+            ..Default::default()
+        })
+    }
+
+    /// 在当前聊天室中从后往前查找消息
+    fn find_message(&self, predicate: impl Fn(&ChatMessage) -> bool) -> Option<&ChatMessage> {
+        self.current_lines()
+            .iter()
+            .rev()
+            .find_map(|line| match line {
+                ChatLine::Message(message) if predicate(message) => Some(message),
+                _ => None,
+            })
+    }
+
+    /// 把编辑、删除或回应事件应用到聊天室中已显示的消息上
+    fn update(&mut self, room: &str, event: &ServerMessage) {
+        let Some(lines) = self.lines.get_mut(room) else {
+            return;
+        };
+        for line in lines.iter_mut() {
+            if let ChatLine::Message(message) = line
+                && message.id == event.target_id
+            {
+                message.update(event);
+            }
+        }
+    }
+
+    fn current_lines(&self) -> &[ChatLine] {
+        self.lines
+            .get(&self.current)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
 }
 
 pub struct Ui {
     username: String,
-    chatroom: String,
+    /// 显示在标题中的连接状态, 例如断线重连中
+    pub banner: Option<String>,
     terminal: Terminal<CrosstermBackend<io::Stdout>>,
     list_state: ListState,
+    /// 翻页查看之前的消息时所在的聊天室和选中的消息, None 表示自动跟随最新消息
+    scroll: Option<(String, usize)>,
+    /// 上次绘制时消息列表能显示的行数, 作为翻页的消息数量
+    page_size: usize,
+}
+
+/// 聊天室中的一条聊天消息, 收到编辑、删除和回应后原地更新
+pub struct ChatMessage {
+    id: String,
+    seq: u64,
+    time: String,
+    username: String,
+    /// 是否为自己发送的消息
+    mine: bool,
+    content: String,
+    edited: bool,
+    deleted: bool,
+    reactions: Vec<Reaction>,
+    /// 已读到这条消息的其他用户
+    seen_by: Vec<String>,
+}
+
+impl ChatMessage {
+    fn new(reply: &ServerMessage, time: String, mine: bool) -> Self {
+        ChatMessage {
+            id: reply.id.clone(),
+            seq: reply.seq,
+            time,
+            username: reply.username.clone(),
+            mine,
+            content: reply.content.clone(),
+            edited: reply.edited,
+            deleted: reply.deleted,
+            reactions: reply.reactions.clone(),
+            seen_by: vec![],
+        }
+    }
+
+    /// 应用编辑、删除或回应事件
+    fn update(&mut self, event: &ServerMessage) {
+        match event.r#type() {
+            Type::Edit => {
+                self.content = event.content.clone();
+                self.edited = true;
+            }
+            Type::Delete => {
+                self.content.clear();
+                self.deleted = true;
+                self.reactions.clear();
+            }
+            _ => self.reactions = event.reactions.clone(),
+        }
+    }
+
+    fn text(&self) -> String {
+        let author = match self.mine {
+            true => "You",
+            false => &self.username,
+        };
+        let mut text = format!("[{} #{}] ", self.time, self.seq);
+        if self.deleted {
+            text.push_str(&format!("{author}: (message deleted)"));
+            return text;
+        }
+        match self.content.strip_prefix(ME_PREFIX) {
+            Some(action) => text.push_str(&format!("* {} {action}", self.username)),
+            None => text.push_str(&format!("{author}: {}", self.content)),
+        }
+        if self.edited {
+            text.push_str(" (edited)");
+        }
+        for reaction in &self.reactions {
+            text.push_str(&format!(
+                "  {} {}",
+                reaction.emoji,
+                reaction.usernames.len()
+            ));
+        }
+        if !self.seen_by.is_empty() {
+            text.push_str(&format!("  ✓ seen by {}", self.seen_by.join(", ")));
+        }
+        text
+    }
+}
+
+/// 消息列表中的一行
+pub enum ChatLine {
+    /// 聊天消息
+    Message(ChatMessage),
+    /// 用户进出等事件和错误提示, 与聊天消息区分显示
+    Notice(String),
+    /// 收发的私信
+    Direct(String),
+}
+
+impl ChatLine {
+    /// 按消息列表的宽度折成多行显示
+    fn to_list_item(&self, width: usize) -> ListItem<'static> {
+        let (text, style) = match self {
+            ChatLine::Message(message) => (message.text(), Style::default()),
+            ChatLine::Notice(text) => (
+                text.clone(),
+                Style::default()
+                    .fg(Color::DarkGray)
+                    .add_modifier(Modifier::ITALIC),
+            ),
+            ChatLine::Direct(text) => (text.clone(), Style::default().fg(Color::Magenta)),
+        };
+        let lines: Vec<Spans> = text
+            .lines()
+            .flat_map(|line| wrap_line(line, width))
+            .map(Spans::from)
+            .collect();
+        ListItem::new(lines).style(style)
+    }
+}
+
+/// 按显示宽度把一行文本折成多行, 尽量在空格处折行, 比一行还宽的词按字符折断
+fn wrap_line(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    let mut line = String::new();
+    let mut line_width = 0;
+    for word in text.split_inclusive(' ') {
+        let word_width = word.trim_end_matches(' ').width();
+        if line_width + word_width > width && !line.is_empty() {
+            lines.push(line.trim_end().to_string());
+            line.clear();
+            line_width = 0;
+        }
+        if word_width <= width {
+            line.push_str(word);
+            line_width += word.width();
+            continue;
+        }
+        for c in word.chars() {
+            let char_width = c.width().unwrap_or(0);
+            if line_width + char_width > width && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            line.push(c);
+            line_width += char_width;
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line.trim_end().to_string());
+    }
+    lines
 }
 
 pub enum UiEvent {
     Enter,
-    Backspace,
     Resize,
     Char(char),
+    /// 编辑输入框, 由 [`LineEditor`] 处理
+    Edit(EditKey),
+    /// 消息列表向上翻页, 暂停自动跟随最新消息
+    PageUp,
+    /// 消息列表向下翻页, 到底后恢复自动跟随
+    PageDown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKey {
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    /// 移到前一个词的开头
+    WordLeft,
+    /// 移到后一个词的结尾
+    WordRight,
+    Home,
+    End,
+    /// 删除光标前的一个词
+    DeleteWord,
+    /// 上一条输入历史
+    HistoryPrev,
+    /// 下一条输入历史, 越过最新一条后回到正在编辑的内容
+    HistoryNext,
+}
+
+/// 输入框的行编辑器, 支持光标移动、按词删除和输入历史
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: String,
+    /// 光标所在的字节位置, 总在字符边界上
+    cursor: usize,
+    /// 发送过的输入, 旧的在前
+    history: Vec<String>,
+    /// 正在浏览的输入历史, None 表示在编辑新的一行
+    history_index: Option<usize>,
+    /// 开始浏览输入历史前正在编辑的内容
+    draft: String,
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.line
+    }
+
+    /// 光标之前的内容的显示宽度
+    pub fn cursor_width(&self) -> usize {
+        self.line[..self.cursor].width()
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// 取出输入的一行并清空输入框, 非空的输入记入历史
+    pub fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.history_index = None;
+        self.draft.clear();
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            if self.history.len() > INPUT_HISTORY_LEN {
+                self.history.remove(0);
+            }
+        }
+        line
+    }
+
+    pub fn edit(&mut self, key: EditKey) {
+        match key {
+            EditKey::Backspace => {
+                let start = self.prev_boundary(self.cursor);
+                self.line.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            EditKey::Delete => {
+                let end = self.next_boundary(self.cursor);
+                self.line.replace_range(self.cursor..end, "");
+            }
+            EditKey::Left => self.cursor = self.prev_boundary(self.cursor),
+            EditKey::Right => self.cursor = self.next_boundary(self.cursor),
+            EditKey::WordLeft => self.cursor = self.word_start(),
+            EditKey::WordRight => self.cursor = self.word_end(),
+            EditKey::Home => self.cursor = 0,
+            EditKey::End => self.cursor = self.line.len(),
+            EditKey::DeleteWord => {
+                let start = self.word_start();
+                self.line.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            EditKey::HistoryPrev => {
+                let index = match self.history_index {
+                    None if self.history.is_empty() => return,
+                    None => {
+                        self.draft = std::mem::take(&mut self.line);
+                        self.history.len() - 1
+                    }
+                    Some(index) => index.saturating_sub(1),
+                };
+                self.history_index = Some(index);
+                self.set_line(self.history[index].clone());
+            }
+            EditKey::HistoryNext => {
+                let Some(index) = self.history_index else {
+                    return;
+                };
+                if index + 1 < self.history.len() {
+                    self.history_index = Some(index + 1);
+                    self.set_line(self.history[index + 1].clone());
+                } else {
+                    self.history_index = None;
+                    let draft = std::mem::take(&mut self.draft);
+                    self.set_line(draft);
+                }
+            }
+        }
+    }
+
+    fn set_line(&mut self, line: String) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+
+    fn prev_boundary(&self, position: usize) -> usize {
+        self.line[..position]
+            .chars()
+            .next_back()
+            .map_or(position, |c| position - c.len_utf8())
+    }
+
+    fn next_boundary(&self, position: usize) -> usize {
+        self.line[position..]
+            .chars()
+            .next()
+            .map_or(position, |c| position + c.len_utf8())
+    }
+
+    /// 光标前一个词的开头, 先跳过光标前的空白
+    fn word_start(&self) -> usize {
+        let before = self.line[..self.cursor].trim_end();
+        before.rfind(char::is_whitespace).map_or(0, |index| {
+            index + before[index..].chars().next().unwrap().len_utf8()
+        })
+    }
+
+    /// 光标后一个词的结尾, 先跳过光标后的空白
+    fn word_end(&self) -> usize {
+        let after = &self.line[self.cursor..];
+        let skipped = after.len() - after.trim_start().len();
+        let word = &after[skipped..];
+        let end = word.find(char::is_whitespace).unwrap_or(word.len());
+        self.cursor + skipped + end
+    }
 }
 
 impl Ui {
-    pub fn new(username: &str, chatroom: &str) -> anyhow::Result<Self> {
+    pub fn new(username: &str) -> anyhow::Result<Self> {
         // 启动 TUI
         enable_raw_mode()?;
         let mut stdout = io::stdout();
@@ -237,21 +1763,78 @@ impl Ui {
         terminal.clear()?;
         Ok(Self {
             username: username.into(),
-            chatroom: chatroom.into(),
+            banner: None,
             terminal,
             list_state: Default::default(),
+            scroll: None,
+            page_size: 1,
         })
     }
 
-    pub fn draw(&mut self, messages: &[String], input: &str) -> anyhow::Result<()> {
-        self.list_state
-            .select(Some(messages.len().saturating_sub(1)));
+    /// 当前聊天室中选中的消息, 切换聊天室后回到自动跟随
+    fn selected(&self, rooms: &Rooms) -> Option<usize> {
+        let len = rooms.current_lines().len();
+        match &self.scroll {
+            Some((room, selected)) if *room == rooms.current => {
+                Some((*selected).min(len.saturating_sub(1)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn page_up(&mut self, rooms: &Rooms) {
+        let last = rooms.current_lines().len().saturating_sub(1);
+        let selected = self.selected(rooms).unwrap_or(last);
+        self.scroll = Some((
+            rooms.current.clone(),
+            selected.saturating_sub(self.page_size),
+        ));
+    }
+
+    pub fn page_down(&mut self, rooms: &Rooms) {
+        let len = rooms.current_lines().len();
+        self.scroll = self
+            .selected(rooms)
+            .map(|selected| selected + self.page_size)
+            .filter(|selected| selected + 1 < len)
+            .map(|selected| (rooms.current.clone(), selected));
+    }
+
+    pub fn draw(
+        &mut self,
+        rooms: &Rooms,
+        members: &[String],
+        input: &LineEditor,
+    ) -> anyhow::Result<()> {
+        let messages = rooms.current_lines();
+        let last = messages.len().saturating_sub(1);
+        let selected = self.selected(rooms);
+        self.list_state.select(Some(selected.unwrap_or(last)));
+        // 去掉边距、输入框、边框和输入状态行
+        self.page_size = (self.terminal.size()?.height as usize)
+            .saturating_sub(8)
+            .max(1);
+        // 标题为 用户名@当前聊天室, 断线重连期间后面跟着醒目的提示
+        let mut title = vec![Span::raw(format!("{}@{}", self.username, rooms.current))];
+        if let Some(banner) = &self.banner {
+            title.push(Span::styled(
+                format!(" - {banner}"),
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ));
+        }
+        if let Some(selected) = selected {
+            title.push(Span::styled(
+                format!(" - {} newer, PageDown to follow", last - selected),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
         self.terminal.draw(|f| {
             Self::render_ui(
                 f,
-                &self.username,
-                &self.chatroom,
+                Spans::from(title),
+                rooms,
                 messages,
+                members,
                 input,
                 &mut self.list_state,
             );
@@ -261,10 +1844,11 @@ impl Ui {
 
     fn render_ui<B: tui::backend::Backend>(
         f: &mut Frame<B>,
-        username: &str,
-        chatroom: &str,
-        messages: &[String],
-        input: &str,
+        title: Spans,
+        rooms: &Rooms,
+        messages: &[ChatLine],
+        members: &[String],
+        input: &LineEditor,
         list_state: &mut ListState,
     ) {
         let chunks = Layout::default()
@@ -273,23 +1857,82 @@ impl Ui {
             .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
             .split(f.size());
 
-        let items: Vec<ListItem> = messages.iter().map(|m| ListItem::new(m.as_str())).collect();
+        let top_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(1), Constraint::Length(MEMBER_LIST_WIDTH)].as_ref())
+            .split(chunks[0]);
+
+        let message_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
+            .split(top_chunks[0]);
+
+        let width = message_chunks[0].width.saturating_sub(2) as usize;
+        let items: Vec<ListItem> = messages
+            .iter()
+            .map(|line| line.to_list_item(width))
+            .collect();
+
+        let message_list =
+            List::new(items).block(Block::default().borders(Borders::ALL).title(title));
+        f.render_stateful_widget(message_list, message_chunks[0], list_state);
+
+        let typing = Paragraph::new(rooms.typing_line()).style(
+            Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC),
+        );
+        f.render_widget(typing, message_chunks[1]);
+
+        let side_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(rooms.joined.len() as u16 + 2),
+                    Constraint::Min(1),
+                ]
+                .as_ref(),
+            )
+            .split(top_chunks[1]);
 
-        let message_list = List::new(items).block(
+        let room_items: Vec<ListItem> = rooms
+            .joined
+            .iter()
+            .map(|room| match *room == rooms.current {
+                true => ListItem::new(format!("> {room}")).style(
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                false => ListItem::new(format!("  {room}")),
+            })
+            .collect();
+        let room_list = List::new(room_items).block(
             Block::default()
                 .borders(Borders::ALL)
-                .title(format!("{username}@{chatroom}")),
+                .title(format!("Rooms({})", rooms.joined.len())),
         );
-        f.render_stateful_widget(message_list, chunks[0], list_state);
+        f.render_widget(room_list, side_chunks[0]);
 
-        let input_box =
-            Paragraph::new(input).block(Block::default().borders(Borders::ALL).title("Input"));
+        let member_items: Vec<ListItem> =
+            members.iter().map(|m| ListItem::new(m.as_str())).collect();
+        let member_list = List::new(member_items).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Members({})", members.len())),
+        );
+        f.render_widget(member_list, side_chunks[1]);
+
+        // 输入超出输入框时横向滚动, 保持光标可见
+        let input_width = chunks[1].width.saturating_sub(3) as usize;
+        let cursor = input.cursor_width();
+        let offset = cursor.saturating_sub(input_width);
+        let input_box = Paragraph::new(input.text())
+            .scroll((0, offset as u16))
+            .block(Block::default().borders(Borders::ALL).title("Input"));
         f.render_widget(input_box, chunks[1]);
 
-        f.set_cursor(
-            chunks[1].x + UnicodeWidthStr::width(input) as u16 + 1,
-            chunks[1].y + 1,
-        );
+        f.set_cursor(chunks[1].x + (cursor - offset) as u16 + 1, chunks[1].y + 1);
     }
 
     pub fn cleanup(&mut self) -> anyhow::Result<()> {
@@ -298,3 +1941,97 @@ impl Ui {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_editor_moves_by_char_and_word() {
+        let mut editor = LineEditor::default();
+        for c in "你好 wide world".chars() {
+            editor.insert(c);
+        }
+        editor.edit(EditKey::DeleteWord);
+        assert_eq!(editor.text(), "你好 wide ");
+        editor.edit(EditKey::WordLeft);
+        editor.edit(EditKey::Left);
+        assert_eq!(editor.cursor_width(), 4);
+        editor.edit(EditKey::Backspace);
+        assert_eq!(editor.text(), "你 wide ");
+        editor.edit(EditKey::WordRight);
+        editor.insert('!');
+        assert_eq!(editor.text(), "你 wide! ");
+        editor.edit(EditKey::Home);
+        editor.edit(EditKey::Delete);
+        assert_eq!(editor.text(), " wide! ");
+    }
+
+    #[test]
+    fn line_editor_recalls_history_and_draft() {
+        let mut editor = LineEditor::default();
+        for line in ["first", "second"] {
+            line.chars().for_each(|c| editor.insert(c));
+            assert_eq!(editor.submit(), line);
+        }
+        editor.insert('x');
+        editor.edit(EditKey::HistoryPrev);
+        assert_eq!(editor.text(), "second");
+        editor.edit(EditKey::HistoryPrev);
+        editor.edit(EditKey::HistoryPrev);
+        assert_eq!(editor.text(), "first");
+        editor.edit(EditKey::HistoryNext);
+        editor.edit(EditKey::HistoryNext);
+        assert_eq!(editor.text(), "x");
+    }
+
+    #[test]
+    fn reconnect_retries_duplicate_login_until_cut_off() {
+        let duplicate = Status::already_exists("already connected");
+        assert!(is_retryable(&duplicate, Duration::ZERO));
+        assert!(is_retryable(
+            &duplicate,
+            DUPLICATE_LOGIN_RETRY - Duration::from_secs(1)
+        ));
+        assert!(!is_retryable(&duplicate, DUPLICATE_LOGIN_RETRY));
+
+        let unavailable = Status::unavailable("server is shutting down");
+        assert!(is_retryable(&unavailable, DUPLICATE_LOGIN_RETRY * 10));
+        assert!(!is_retryable(
+            &Status::permission_denied("banned"),
+            Duration::ZERO
+        ));
+    }
+
+    #[test]
+    fn wrap_line_by_display_width() {
+        assert_eq!(wrap_line("hello big world", 9), ["hello big", "world"]);
+        assert_eq!(wrap_line("abcdefgh", 3), ["abc", "def", "gh"]);
+        assert_eq!(wrap_line("你好世界", 5), ["你好", "世界"]);
+        assert_eq!(wrap_line("", 5), [""]);
+    }
+
+    #[test]
+    fn handle_input_dispatches_commands() {
+        let mut rooms = Rooms::new("public");
+        let CommandAction::Send(request) = handle_input(&mut rooms, "/me waves") else {
+            panic!("expect a message");
+        };
+        assert_eq!(request.content, "/me waves");
+        let CommandAction::Send(request) = handle_input(&mut rooms, "//shrug") else {
+            panic!("expect a message");
+        };
+        assert_eq!(request.content, "/shrug");
+        assert_eq!(handle_input(&mut rooms, "/quit"), CommandAction::Quit);
+        assert_eq!(
+            handle_input(&mut rooms, "/nick bob"),
+            CommandAction::Nick("bob".into())
+        );
+        // 未知命令和参数错误只在本地显示提示
+        assert_eq!(handle_input(&mut rooms, "/nick"), CommandAction::Done);
+        assert_eq!(handle_input(&mut rooms, "/dance"), CommandAction::Done);
+        assert_eq!(rooms.lines["public"].len(), COMMANDS.len() + 3);
+        assert_eq!(handle_input(&mut rooms, "/clear"), CommandAction::Done);
+        assert!(!rooms.lines.contains_key("public"));
+    }
+}
@@ -1,9 +1,20 @@
-use clap::Parser;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::{Parser, ValueEnum};
+use instant_chat::auth::Authenticator;
+use instant_chat::chat_repository::ChatRepository;
+use instant_chat::memory_repository::MemoryRepository;
+use instant_chat::observability;
+use instant_chat::rate_limit::{RateLimit, RateLimitAction, RateLimitOptions};
 use instant_chat::stub::instant_chat_server::InstantChatServer;
-use instant_chat::valkey_chat_service::ValkeyChatService;
+use instant_chat::subscriber::{BufferOptions, OverflowPolicy};
+use instant_chat::valkey_chat_service::{ChatOptions, DuplicateLoginPolicy, ValkeyChatService};
+use instant_chat::valkey_repository::ValkeyRepository;
+use tokio::net::TcpListener;
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
-use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 use tonic_reflection::server::Builder;
 use tracing::info;
 use tracing_subscriber::EnvFilter;
@@ -16,13 +27,101 @@ struct Args {
     #[arg(long, default_value = "0.0.0.0:50051")]
     addr: String,
 
+    /// Message backend, `memory` runs a single node without any external service
+    #[arg(long, value_enum, default_value_t = Backend::Valkey)]
+    backend: Backend,
+
     /// Valkey/Redis host:port, e.g. 127.0.0.1:6379
     #[arg(long, default_value = "127.0.0.1:6379")]
     valkey_addr: String,
 
     /// Valkey/Redis password
     #[arg(long, env = "VALKEY_PASSWORD")]
-    valkey_password: String,
+    valkey_password: Option<String>,
+
+    /// Approximate number of messages kept in each chatroom history
+    #[arg(long, default_value_t = 1000)]
+    history_max_len: usize,
+
+    /// Number of recent messages replayed to a newly connected user, 0 to disable
+    #[arg(long, default_value_t = 50)]
+    history_replay: usize,
+
+    /// Seconds a connection stays in chatroom member list without heartbeat
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(3..))]
+    presence_ttl_secs: u64,
+
+    /// How to handle a user connecting to a chatroom it is already in
+    #[arg(long, value_enum, default_value_t = DuplicateLoginPolicy::Kick)]
+    duplicate_login: DuplicateLoginPolicy,
+
+    /// Messages buffered for each connection before the overflow policy applies
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    subscriber_buffer: u64,
+
+    /// What to do when a connection's buffer is full because the client reads too slowly
+    #[arg(long, value_enum, default_value_t = OverflowPolicy::DropOldest)]
+    subscriber_overflow: OverflowPolicy,
+
+    /// Messages per second each user may send across all its connections, 0 for no limit
+    #[arg(long, default_value_t = 0.0)]
+    user_rate_limit: f64,
+
+    /// Messages a user may send in a burst before the per-user rate applies
+    #[arg(long, default_value_t = 10)]
+    user_rate_burst: u32,
+
+    /// Messages per second all users of a chatroom may send together, 0 for no limit
+    #[arg(long, default_value_t = 0.0)]
+    room_rate_limit: f64,
+
+    /// Messages a chatroom may receive in a burst before the per-room rate applies
+    #[arg(long, default_value_t = 50)]
+    room_rate_burst: u32,
+
+    /// How to handle a message over the rate limit
+    #[arg(long, value_enum, default_value_t = RateLimitAction::Drop)]
+    rate_limit_action: RateLimitAction,
+
+    /// Where rate limit buckets are kept, `valkey` shares limits between server instances
+    #[arg(long, value_enum, default_value_t = RateLimitStore::Local)]
+    rate_limit_store: RateLimitStore,
+
+    /// Seconds to wait on shutdown for connected clients to be notified and their disconnect
+    /// messages published
+    #[arg(long, default_value_t = 10)]
+    shutdown_grace_secs: u64,
+
+    /// Address of the HTTP server exposing Prometheus metrics at /metrics
+    #[arg(long, default_value = "0.0.0.0:9100")]
+    metrics_addr: String,
+
+    /// Maximum number of chatrooms with their own label in per-room metrics, the rest are
+    /// counted together as room="_other"
+    #[arg(long, default_value_t = 100)]
+    metrics_room_cap: usize,
+
+    /// Maximum number of chatrooms one connection can join, including the chatroom in metadata
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    max_rooms_per_session: u64,
+
+    /// Server-wide moderator, may edit and delete any message and moderate every chatroom,
+    /// can be given multiple times
+    #[arg(long)]
+    moderator: Vec<String>,
+
+    /// Seconds between health checks, which PING the backend to report SERVING/NOT_SERVING
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_interval_secs: u64,
+
+    /// HMAC secret to verify bearer tokens(JWT, HS256), authentication is disabled if neither
+    /// secret nor secret file is given
+    #[arg(long, env = "AUTH_SECRET", conflicts_with = "auth_secret_file")]
+    auth_secret: Option<String>,
+
+    /// File containing the HMAC secret to verify bearer tokens
+    #[arg(long)]
+    auth_secret_file: Option<String>,
 
     #[arg(long, help = "TLS certificate file")]
     tls_cert: String,
@@ -30,6 +129,11 @@ struct Args {
     #[arg(long, help = "TLS key file")]
     tls_key: String,
 
+    /// CA file to verify client certificates, enables mutual TLS and takes username from the
+    /// client certificate's CN/SAN instead of metadata
+    #[arg(long)]
+    tls_client_ca: Option<String>,
+
     #[arg(
         long,
         env = "RUST_LOG",
@@ -47,10 +151,29 @@ struct Args {
     log_json: bool,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    /// In-process broadcast, messages are not shared between server instances
+    Memory,
+    /// Valkey/Redis publish/subscribe and streams
+    Valkey,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RateLimitStore {
+    /// In-process buckets, each server instance limits separately
+    Local,
+    /// Buckets in Valkey/Redis, requires the valkey backend
+    Valkey,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let addr = args.addr.parse()?;
+    if args.rate_limit_store == RateLimitStore::Valkey && args.backend != Backend::Valkey {
+        anyhow::bail!("--rate-limit-store valkey requires --backend valkey");
+    }
+    let addr: SocketAddr = args.addr.parse()?;
 
     let cert = tokio::fs::read(args.tls_cert).await?;
     let key = tokio::fs::read(args.tls_key).await?;
@@ -71,34 +194,165 @@ async fn main() -> anyhow::Result<()> {
             .init();
     }
 
+    let mut tls_config = ServerTlsConfig::new().identity(identity);
+    let mut authenticator = match (&args.auth_secret, &args.auth_secret_file) {
+        (Some(secret), _) => Authenticator::disabled().with_hmac_secret(secret.as_bytes()),
+        (None, Some(file)) => {
+            let secret = tokio::fs::read_to_string(file).await?;
+            Authenticator::disabled().with_hmac_secret(secret.trim_end().as_bytes())
+        }
+        (None, None) => Authenticator::disabled(),
+    };
+    if let Some(client_ca) = &args.tls_client_ca {
+        let client_ca = tokio::fs::read(client_ca).await?;
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(client_ca));
+        authenticator = authenticator.with_client_certificate();
+    }
+
     let shutdown_token = CancellationToken::new();
+    let serve_options = ServeOptions {
+        shutdown_grace: Duration::from_secs(args.shutdown_grace_secs),
+        health_check_interval: Duration::from_secs(args.health_check_interval_secs),
+        metrics_addr: args.metrics_addr.parse()?,
+    };
 
-    // URL form: redist://:password@host:port/?option=value
-    let valkey_url = format!(
-        "redis://:{}@{}/?protocol=resp3",
-        &args.valkey_password, &args.valkey_addr
-    );
-    let chat_service = ValkeyChatService::new(&valkey_url, shutdown_token.clone()).await?;
+    let chat_options = ChatOptions {
+        history_max_len: args.history_max_len,
+        history_replay: args.history_replay,
+        presence_ttl: Duration::from_secs(args.presence_ttl_secs),
+        duplicate_login: args.duplicate_login,
+        subscriber_buffer: BufferOptions {
+            capacity: args.subscriber_buffer as usize,
+            overflow: args.subscriber_overflow,
+        },
+        rate_limit: RateLimitOptions {
+            per_user: RateLimit::new(args.user_rate_limit, args.user_rate_burst),
+            per_room: RateLimit::new(args.room_rate_limit, args.room_rate_burst),
+            action: args.rate_limit_action,
+        },
+        metrics_room_cap: args.metrics_room_cap,
+        max_rooms_per_session: args.max_rooms_per_session as usize,
+        moderators: args.moderator.into_iter().collect(),
+    };
+    match args.backend {
+        Backend::Memory => {
+            let repository = MemoryRepository::new(chat_options.history_max_len);
+            let chat_service = ValkeyChatService::with_repository(
+                repository,
+                chat_options,
+                shutdown_token.clone(),
+            );
+            serve(addr, tls_config, authenticator, chat_service, serve_options).await
+        }
+        Backend::Valkey => {
+            // URL form: redist://:password@host:port/?option=value
+            let valkey_url = match &args.valkey_password {
+                Some(password) => format!(
+                    "redis://:{}@{}/?protocol=resp3",
+                    password, &args.valkey_addr
+                ),
+                None => format!("redis://{}/?protocol=resp3", &args.valkey_addr),
+            };
+            let repository =
+                ValkeyRepository::new(&valkey_url, chat_options.history_max_len).await?;
+            let rate_limiter = repository.rate_limiter();
+            let mut chat_service = ValkeyChatService::with_repository(
+                repository,
+                chat_options,
+                shutdown_token.clone(),
+            );
+            if args.rate_limit_store == RateLimitStore::Valkey {
+                chat_service = chat_service.with_rate_limiter(rate_limiter);
+            }
+            serve(addr, tls_config, authenticator, chat_service, serve_options).await
+        }
+    }
+}
 
+struct ServeOptions {
+    shutdown_grace: Duration,
+    health_check_interval: Duration,
+    metrics_addr: SocketAddr,
+}
+
+async fn serve<R: ChatRepository>(
+    addr: SocketAddr,
+    tls_config: ServerTlsConfig,
+    authenticator: Authenticator,
+    chat_service: ValkeyChatService<R>,
+    options: ServeOptions,
+) -> anyhow::Result<()> {
     info!(?addr, "starting instant chat server");
 
+    let drain = chat_service.drain_handle();
+    let shutdown_grace = options.shutdown_grace;
+
+    // 指标在关闭流程中仍然可用, gRPC server 退出后再关闭
+    let metrics_shutdown = CancellationToken::new();
+    let metrics_listener = TcpListener::bind(options.metrics_addr).await?;
+    let metrics_server = tokio::spawn(observability::serve_metrics(
+        metrics_listener,
+        observability::setup_metrics_recorder(),
+        metrics_shutdown.clone().cancelled_owned(),
+    ));
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(
+        chat_service
+            .health_probe()
+            .report(health_reporter, options.health_check_interval),
+    );
+
     let reflection_service = Builder::configure()
         .register_encoded_file_descriptor_set(instant_chat::stub::INSTANTCHAT_DESCRIPTOR)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
         .build_v1()
         .unwrap();
 
     Server::builder()
-        .tls_config(ServerTlsConfig::new().identity(identity))?
-        .add_service(InstantChatServer::new(chat_service))
+        .tls_config(tls_config)?
+        .add_service(InstantChatServer::with_interceptor(
+            chat_service,
+            authenticator,
+        ))
+        .add_service(health_service)
         .add_service(reflection_service)
         .serve_with_shutdown(addr, async {
-            signal::ctrl_c()
-                .await
-                .expect("Failed to install CTRL+C handler");
-            info!("shutting down server ...");
-            shutdown_token.cancel();
+            shutdown_signal().await;
+            info!(
+                ?shutdown_grace,
+                "shutting down server, draining connections ..."
+            );
+            if drain.drain(shutdown_grace).await {
+                info!("all connections drained");
+            }
         })
         .await?;
 
+    metrics_shutdown.cancel();
+    metrics_server.await??;
     Ok(())
 }
+
+/// 等待 SIGINT(Ctrl+C) 或 SIGTERM(容器编排系统停止容器时发送).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install CTRL+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
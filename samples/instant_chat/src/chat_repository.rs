@@ -0,0 +1,478 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::subscriber::{BufferOptions, SubscriberReceiver};
+
+/// 频道消息的类型, 对应 proto 中的 `Type`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// 用户发送的聊天消息
+    #[default]
+    Message,
+    /// 用户进入聊天室, `username` 为进入的用户
+    Connect,
+    /// 用户离开聊天室, `username` 为离开的用户
+    Disconnect,
+    /// 强制断开 `username` 的连接, 只通过 [`ChatChannel::notify`] 发送, 不保存到历史
+    Kick,
+    /// `username` 发给 `recipient` 的私信, 通过 [`user_channel`] 发送
+    Direct,
+    /// `username` 把 `target_id` 消息的内容改为 `content`
+    Edit,
+    /// `username` 删除了 `target_id` 消息
+    Delete,
+    /// `username` 对 `target_id` 消息添加或取消回应 `content`, `reactions` 为之后的全部回应
+    Reaction,
+    /// `username` 正在输入, 只通过 [`ChatChannel::notify`] 发送
+    Typing,
+    /// `username` 已读到 `target_id` 消息, 只通过 [`ChatChannel::notify`] 发送
+    Read,
+    /// `username` 对 `target_user` 执行了管理操作 `moderation`, `content` 为原因
+    Moderation,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ChannelMessage {
+    /// 消息在频道历史中的 ID, 发布时由存储后端生成.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    /// 频道内递增的序号, 发布时由存储后端分配, 客户端据此排序和发现丢失的消息.
+    #[serde(default)]
+    pub seq: u64,
+    /// 发布时间, 由服务端盖上.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub kind: MessageKind,
+    pub username: String,
+    pub content: String,
+    /// 消息针对的连接 ID, 用于 Kick, 为空表示该用户的所有连接.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub session_id: String,
+    /// 私信的接收者.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub recipient: String,
+    /// 编辑、删除和回应事件针对的消息 ID.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target_id: String,
+    /// 聊天消息是否被编辑过.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    /// 聊天消息是否已被删除, 删除后内容为空.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// 聊天消息收到的回应, 回应 -> 按回应顺序排列的用户名.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, Vec<String>>,
+    /// 管理事件的操作.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<ModerationAction>,
+    /// 管理事件针对的用户.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub target_user: String,
+}
+
+impl ChannelMessage {
+    /// 是否为断开该连接的 Kick 消息.
+    pub fn kicks(&self, member: &Member) -> bool {
+        self.kind == MessageKind::Kick
+            && self.username == member.username
+            && (self.session_id.is_empty() || self.session_id == member.session_id)
+    }
+
+    /// 是否为把该用户移出聊天室的管理事件, 该用户的所有连接都离开.
+    pub fn removes(&self, member: &Member) -> bool {
+        self.kind == MessageKind::Moderation
+            && self.target_user == member.username
+            && self.moderation.is_some_and(ModerationAction::removes)
+    }
+
+    /// 把编辑、删除或回应事件应用到本消息, 更新为最新状态, 返回要发布的事件.
+    /// 只有作者和 `moderator` 可以编辑和删除, 任何人都可以回应. 回应事件带上之后的全部回应.
+    pub fn apply(
+        &mut self,
+        event: &ChannelMessage,
+        moderator: bool,
+    ) -> Result<ChannelMessage, UpdateRejected> {
+        if self.kind != MessageKind::Message {
+            return Err(UpdateRejected::NotFound);
+        }
+        if self.deleted {
+            return Err(UpdateRejected::Deleted);
+        }
+        if event.kind != MessageKind::Reaction && event.username != self.username && !moderator {
+            return Err(UpdateRejected::NotAllowed);
+        }
+        let mut event = event.clone();
+        match event.kind {
+            MessageKind::Edit => {
+                self.content = event.content.clone();
+                self.edited = true;
+            }
+            MessageKind::Delete => {
+                self.content.clear();
+                self.deleted = true;
+                self.reactions.clear();
+            }
+            _ => {
+                let usernames = self.reactions.entry(event.content.clone()).or_default();
+                match usernames.iter().position(|name| *name == event.username) {
+                    Some(index) => {
+                        usernames.remove(index);
+                    }
+                    None => usernames.push(event.username.clone()),
+                }
+                if usernames.is_empty() {
+                    self.reactions.remove(&event.content);
+                }
+                event.reactions = self.reactions.clone();
+            }
+        }
+        Ok(event)
+    }
+}
+
+/// 编辑、删除或回应被拒绝的原因.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateRejected {
+    /// 目标消息不存在, 已不在历史中, 或者不是聊天消息
+    NotFound,
+    /// 只有作者和管理员可以编辑和删除消息
+    NotAllowed,
+    /// 目标消息已被删除
+    Deleted,
+}
+
+impl UpdateRejected {
+    /// 存储后端使用的原因代码.
+    pub fn code(&self) -> &'static str {
+        match self {
+            UpdateRejected::NotFound => "not_found",
+            UpdateRejected::NotAllowed => "not_allowed",
+            UpdateRejected::Deleted => "deleted",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        [
+            UpdateRejected::NotFound,
+            UpdateRejected::NotAllowed,
+            UpdateRejected::Deleted,
+        ]
+        .into_iter()
+        .find(|rejected| rejected.code() == code)
+    }
+}
+
+impl fmt::Display for UpdateRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateRejected::NotFound => write!(f, "message not found"),
+            UpdateRejected::NotAllowed => {
+                write!(f, "only the author or a moderator can change the message")
+            }
+            UpdateRejected::Deleted => write!(f, "message has been deleted"),
+        }
+    }
+}
+
+impl std::error::Error for UpdateRejected {}
+
+/// 聊天室的管理操作, 对应 proto 中的 `ModerationAction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+    Op,
+    Deop,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Kick => "kick",
+            ModerationAction::Ban => "ban",
+            ModerationAction::Unban => "unban",
+            ModerationAction::Mute => "mute",
+            ModerationAction::Unmute => "unmute",
+            ModerationAction::Op => "op",
+            ModerationAction::Deop => "deop",
+        }
+    }
+
+    /// 目标用户是否离开聊天室.
+    pub fn removes(self) -> bool {
+        matches!(self, ModerationAction::Kick | ModerationAction::Ban)
+    }
+
+    /// `actor` 能否对 `target` 执行该操作: 任免版主需要所有者, 其他操作需要版主,
+    /// 并且只能针对角色比自己低的用户.
+    pub fn permits(self, actor: RoomRole, target: RoomRole) -> bool {
+        let required = match self {
+            ModerationAction::Op | ModerationAction::Deop => RoomRole::Owner,
+            _ => RoomRole::Moderator,
+        };
+        actor >= required && actor > target
+    }
+}
+
+impl fmt::Display for ModerationAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 用户在聊天室中的角色, 从低到高排列.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoomRole {
+    Member,
+    Moderator,
+    Owner,
+    /// 服务端的管理员, 在所有聊天室中都是最高角色
+    Admin,
+}
+
+/// 聊天室的管理信息.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoomModeration {
+    /// 第一个加入聊天室的用户, 为空表示还没有所有者
+    pub owner: String,
+    pub moderators: BTreeSet<String>,
+    /// 不能加入聊天室的用户
+    pub banned: BTreeSet<String>,
+    /// 不能在聊天室中发送、编辑、删除和回应消息的用户
+    pub muted: BTreeSet<String>,
+}
+
+impl RoomModeration {
+    /// 用户在聊天室中的角色, `admin` 为是否为服务端管理员.
+    pub fn role(&self, username: &str, admin: bool) -> RoomRole {
+        if admin {
+            RoomRole::Admin
+        } else if self.owner == username {
+            RoomRole::Owner
+        } else if self.moderators.contains(username) {
+            RoomRole::Moderator
+        } else {
+            RoomRole::Member
+        }
+    }
+}
+
+/// 用户频道的前缀, 聊天室名不能以它开头.
+pub const USER_CHANNEL_PREFIX: &str = "@";
+
+/// 每个用户收件箱最多保存的离线私信数量, 超出时丢弃最旧的.
+pub const INBOX_MAX_LEN: usize = 100;
+
+/// 收件箱在最后一条私信之后的有效期.
+pub const INBOX_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// 用户的私信频道, 该用户的每个连接都订阅它.
+pub fn user_channel(username: &str) -> String {
+    format!("{USER_CHANNEL_PREFIX}{username}")
+}
+
+/// 查询频道历史消息的条件.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// 最多返回的消息数量.
+    pub limit: usize,
+    /// 只返回该 ID 之后的消息(不含), 为空时返回最新的消息.
+    pub since_id: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn latest(limit: usize) -> Self {
+        HistoryQuery {
+            limit,
+            since_id: None,
+        }
+    }
+
+    pub fn since(id: &str, limit: usize) -> Self {
+        HistoryQuery {
+            limit,
+            since_id: Some(id.into()),
+        }
+    }
+}
+
+/// 聊天室中的一个连接, 同一用户可以有多个连接.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Member {
+    pub username: String,
+    /// 连接 ID, 由服务端为每个 Chat 调用生成
+    pub session_id: String,
+}
+
+impl Member {
+    pub fn new(username: &str) -> Self {
+        Member {
+            username: username.into(),
+            session_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// 订阅状态的变化, 作为错误随订阅的消息送达订阅者.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    /// 与后端的连接断开, 正在重连, 期间发布的消息收不到
+    Interrupted,
+    /// 重连后已重新订阅, 中断期间的消息可以从历史中补齐
+    Resumed,
+}
+
+impl fmt::Display for SubscriptionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionState::Interrupted => write!(f, "subscription interrupted, reconnecting"),
+            SubscriptionState::Resumed => write!(f, "subscription resumed"),
+        }
+    }
+}
+
+impl std::error::Error for SubscriptionState {}
+
+/// 后端暂时不可用, 例如连接断开, 拒绝连接或超时, 稍后重试可能成功. 各后端把自己的错误归类后
+/// 以它作为错误的上下文.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendUnavailable;
+
+impl fmt::Display for BackendUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chat backend unavailable")
+    }
+}
+
+impl std::error::Error for BackendUnavailable {}
+
+/// 是否为后端暂时不可用导致的错误, 包括订阅中断.
+pub fn is_unavailable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<BackendUnavailable>()
+            || cause.downcast_ref::<SubscriptionState>() == Some(&SubscriptionState::Interrupted)
+    })
+}
+
+pub trait FromChannelMessage: Send + 'static {
+    fn from(message: Result<ChannelMessage>) -> Self;
+}
+
+impl FromChannelMessage for Result<ChannelMessage> {
+    fn from(message: Result<ChannelMessage>) -> Self {
+        message
+    }
+}
+
+/// 聊天消息的存储与分发, 由 Valkey 或进程内实现.
+#[async_trait]
+pub trait ChatRepository: Send + Sync + 'static {
+    type Channel: ChatChannel;
+
+    /// 获取频道的发布者.
+    fn get_channel(&self, channel: &str) -> Self::Channel;
+
+    /// 订阅频道，返回一个 Receiver，外部用异步方式接收消息, token 取消后停止订阅.
+    /// 每个订阅者的缓冲区有界, 消费慢时按 `buffer` 的溢出策略处理.
+    async fn subscribe<T>(
+        &self,
+        channel: &str,
+        buffer: BufferOptions,
+        token: CancellationToken,
+    ) -> Result<SubscriberReceiver<T>>
+    where
+        T: FromChannelMessage;
+
+    /// 读取频道历史消息, 按发布顺序返回(旧消息在前).
+    async fn history(&self, channel: &str, query: &HistoryQuery) -> Result<Vec<ChannelMessage>>;
+
+    /// 连接加入频道, 返回同一用户在该频道中的其他在线连接.
+    /// `exclusive` 为 true 且存在其他连接时不加入. 检查与加入是原子的, 多个服务实例之间同样有效.
+    async fn join_member(
+        &self,
+        channel: &str,
+        member: &Member,
+        ttl: Duration,
+        exclusive: bool,
+    ) -> Result<Vec<Member>>;
+
+    /// 为已加入的连接续期, 超过 `ttl` 未续期的连接视为已离开,
+    /// 避免服务端崩溃后留下不存在的用户.
+    async fn add_member(&self, channel: &str, member: &Member, ttl: Duration) -> Result<()>;
+
+    /// 连接离开频道.
+    async fn remove_member(&self, channel: &str, member: &Member) -> Result<()>;
+
+    /// 频道中在线的用户名, 去重并按字母排序.
+    async fn members(&self, channel: &str) -> Result<Vec<String>>;
+
+    /// 发送私信到接收者的 [`user_channel`]. 接收者没有在线连接时保存到收件箱,
+    /// 等它下次连接时由 [`Self::take_inbox`] 取出. 返回是否已实时送达.
+    /// 检查在线与保存是原子的, 不会在接收者上线的同时丢失.
+    async fn send_direct(&self, message: &ChannelMessage) -> Result<bool>;
+
+    /// 取出并清空用户收件箱中的私信, 按发送顺序返回.
+    async fn take_inbox(&self, username: &str) -> Result<Vec<ChannelMessage>>;
+
+    /// 读取聊天室的管理信息.
+    async fn room_moderation(&self, channel: &str) -> Result<RoomModeration>;
+
+    /// 与 [`Self::room_moderation`] 相同, 聊天室还没有所有者时原子地让 `username` 成为所有者.
+    async fn claim_room(&self, channel: &str, username: &str) -> Result<RoomModeration>;
+
+    /// 按管理操作修改聊天室的版主、封禁和禁言名单, 踢出不修改管理信息.
+    async fn moderate(&self, channel: &str, action: ModerationAction, username: &str)
+    -> Result<()>;
+
+    /// 检查后端是否可用, 用于健康检查.
+    async fn ping(&self) -> Result<()>;
+}
+
+/// 某个频道的发布者.
+#[async_trait]
+pub trait ChatChannel: Send + Sync + 'static {
+    /// 发布消息到频道, 由后端盖上时间戳并分配 ID 和序号, 返回有多少个订阅者.
+    async fn publish(&mut self, message: &ChannelMessage) -> Result<usize>;
+
+    /// 只把消息发送给当前的订阅者, 不保存到历史也不分配 ID 和序号, 返回有多少个订阅者.
+    async fn notify(&mut self, message: &ChannelMessage) -> Result<usize>;
+
+    /// 编辑、删除或回应频道历史中的消息. 按 [`ChannelMessage::apply`] 的规则原子地检查并更新
+    /// 目标消息的最新状态, 之后读取历史时返回更新后的消息, 然后像 [`Self::publish`] 一样发布事件,
+    /// 返回发布的事件. 被拒绝时返回 [`UpdateRejected`] 错误.
+    async fn update(&mut self, event: &ChannelMessage, moderator: bool) -> Result<ChannelMessage>;
+}
+
+/// 判断是否为完整的消息 ID, 形如 `<millis>-<seq>`. Valkey 也接受只有 `<millis>` 的 ID,
+/// 但它会匹配同一毫秒内的所有消息, 因此不算合法的消息 ID.
+pub fn is_stream_id(id: &str) -> bool {
+    let is_number = |s: &str| s.bytes().all(|b| b.is_ascii_digit()) && s.parse::<u64>().is_ok();
+    id.split_once('-')
+        .is_some_and(|(millis, seq)| is_number(millis) && is_number(seq))
+}
+
+/// 比较两个消息 ID, 判断 `id` 是否在 `last` 之后.
+pub fn is_after(id: &str, last: &str) -> bool {
+    parse_stream_id(id) > parse_stream_id(last)
+}
+
+fn parse_stream_id(id: &str) -> (u64, u64) {
+    let (millis, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (millis.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::{MissedTickBehavior, timeout};
+use tonic::server::NamedService;
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
+use tracing::{info, warn};
+
+use crate::chat_repository::ChatRepository;
+use crate::stub::instant_chat_server::InstantChatServer;
+use crate::valkey_chat_service::{Drain, ValkeyChatService};
+
+/// PING 超过该时间未响应视为后端不可用.
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 检查聊天服务是否可用: 未进入关闭流程, 且后端能响应 PING.
+pub struct HealthProbe<R> {
+    repository: Arc<R>,
+    drain: Drain,
+}
+
+impl<R: ChatRepository> HealthProbe<R> {
+    pub fn new(repository: Arc<R>, drain: Drain) -> Self {
+        HealthProbe { repository, drain }
+    }
+
+    pub async fn status(&self) -> ServingStatus {
+        if self.drain.is_draining() {
+            return ServingStatus::NotServing;
+        }
+        match timeout(PING_TIMEOUT, self.repository.ping()).await {
+            Ok(Ok(())) => ServingStatus::Serving,
+            Ok(Err(err)) => {
+                warn!(?err, "health check failed to ping backend");
+                ServingStatus::NotServing
+            }
+            Err(_) => {
+                warn!("health check timed out pinging backend");
+                ServingStatus::NotServing
+            }
+        }
+    }
+
+    /// 每隔 `interval` 检查一次, 更新 `grpc.health.v1` 中整个服务器("")和 InstantChat 服务的状态.
+    /// 进入关闭流程后立即报告 NOT_SERVING 并结束.
+    pub async fn report(self, reporter: HealthReporter, interval: Duration) {
+        let service_name = <InstantChatServer<ValkeyChatService<R>> as NamedService>::NAME;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last = None;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = self.drain.draining() => {},
+            }
+            let status = self.status().await;
+            if last != Some(status) {
+                info!(?status, "health status changed");
+                reporter.set_service_status("", status).await;
+                reporter.set_service_status(service_name, status).await;
+                last = Some(status);
+            }
+            if self.drain.is_draining() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_repository::MemoryRepository;
+    use crate::valkey_chat_service::ChatOptions;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn not_serving_after_drain() {
+        let service = ValkeyChatService::with_repository(
+            MemoryRepository::new(10),
+            ChatOptions::default(),
+            CancellationToken::new(),
+        );
+        let probe = service.health_probe();
+        assert_eq!(probe.status().await, ServingStatus::Serving);
+
+        assert!(service.drain_handle().drain(Duration::from_secs(1)).await);
+        assert_eq!(probe.status().await, ServingStatus::NotServing);
+    }
+}
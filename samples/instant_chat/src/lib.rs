@@ -1,4 +1,16 @@
+// tonic::Status is the error type of every gRPC handler and stream item.
+#![allow(clippy::result_large_err)]
+
+pub mod auth;
+pub mod backoff;
+pub mod chat_repository;
+pub mod health;
+pub mod memory_repository;
+pub mod observability;
+pub mod rate_limit;
+pub mod subscriber;
 pub mod valkey_chat_service;
+pub mod valkey_pubsub;
 pub mod valkey_repository;
 
 #[allow(clippy::all, unused_qualifications)]
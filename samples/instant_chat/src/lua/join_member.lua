@@ -0,0 +1,21 @@
+-- 连接加入频道, 返回同一用户在该频道中的其他在线连接.
+--
+-- KEYS[1] 频道在线连接 sorted set, member 为 `<session_id>/<username>`, score 为过期时间(毫秒)
+-- ARGV[1] 当前时间(毫秒), ARGV[2] 有效期(毫秒), ARGV[3] 连接 member, ARGV[4] 用户名,
+-- ARGV[5] 为 '1' 时若存在其他连接则不加入
+--
+-- 返回同一用户其他连接的 member 列表
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+local others = {}
+for _, entry in ipairs(redis.call('ZRANGE', KEYS[1], 0, -1)) do
+  local slash = string.find(entry, '/', 1, true)
+  if slash and string.sub(entry, slash + 1) == ARGV[4] and entry ~= ARGV[3] then
+    table.insert(others, entry)
+  end
+end
+if ARGV[5] == '1' and #others > 0 then
+  return others
+end
+redis.call('ZADD', KEYS[1], ARGV[1] + ARGV[2], ARGV[3])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return others
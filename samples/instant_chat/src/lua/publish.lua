@@ -0,0 +1,13 @@
+-- 原子地为频道消息分配序号, 追加到历史 stream 并发布到频道.
+--
+-- KEYS[1] 频道历史 stream, KEYS[2] 频道序号计数器
+-- ARGV[1] 频道名, ARGV[2] 消息 JSON, ARGV[3] 历史 stream 大致保留长度
+--
+-- 返回 { stream ID, 序号, 订阅者数量 }
+local seq = redis.call('INCR', KEYS[2])
+local message = cjson.decode(ARGV[2])
+message['seq'] = seq
+local id = redis.call('XADD', KEYS[1], 'MAXLEN', '~', ARGV[3], '*', 'message', cjson.encode(message))
+message['id'] = id
+local receivers = redis.call('PUBLISH', ARGV[1], cjson.encode(message))
+return { id, seq, receivers }
@@ -0,0 +1,22 @@
+-- 令牌桶限流, 从桶中取一个令牌.
+--
+-- KEYS[1] 令牌桶 hash, 字段 tokens 为剩余令牌, at 为上次更新时间(毫秒)
+-- ARGV[1] 当前时间(毫秒), ARGV[2] 每秒补充的令牌数, ARGV[3] 最多积攒的令牌数
+--
+-- 返回 1 表示允许, 0 表示超出限制
+local now = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local burst = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or burst
+local at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * rate / 1000)
+local allowed = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tokens, 'at', now)
+-- 补满之后桶与不存在等价, 让它过期
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000) + 1000)
+return allowed
@@ -0,0 +1,14 @@
+-- 发送私信, 接收者不在线时保存到收件箱.
+--
+-- KEYS[1] 接收者的收件箱 list
+-- ARGV[1] 接收者的用户频道, ARGV[2] 消息 JSON, ARGV[3] 收件箱最多保存的消息数量,
+-- ARGV[4] 收件箱的有效期(毫秒)
+--
+-- 返回订阅者数量, 0 表示已保存到收件箱
+local receivers = redis.call('PUBLISH', ARGV[1], ARGV[2])
+if receivers == 0 then
+  redis.call('RPUSH', KEYS[1], ARGV[2])
+  redis.call('LTRIM', KEYS[1], -tonumber(ARGV[3]), -1)
+  redis.call('PEXPIRE', KEYS[1], ARGV[4])
+end
+return receivers
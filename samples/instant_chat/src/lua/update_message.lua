@@ -0,0 +1,107 @@
+-- 原子地检查权限并更新消息的最新状态, 然后把编辑、删除或回应事件追加到历史 stream 并发布到频道.
+-- 规则与 ChannelMessage::apply 一致.
+--
+-- KEYS[1] 频道历史 stream, KEYS[2] 频道序号计数器, KEYS[3] 消息最新状态 hash(消息 ID -> 消息 JSON)
+-- ARGV[1] 频道名, ARGV[2] 事件 JSON, ARGV[3] 历史 stream 大致保留长度, ARGV[4] 是否为管理员('1' 或 '0')
+--
+-- 返回 { 'ok', 发布的事件 JSON }, 被拒绝时返回 { 原因 }: not_found, not_allowed, deleted
+local function parse_id(id)
+    local millis, seq = string.match(id, '^(%d+)-(%d+)$')
+    return tonumber(millis), tonumber(seq)
+end
+
+-- 无法解析的 ID 视为在最前面, 清理状态时一并删除
+local function is_before(id, other)
+    local millis, seq = parse_id(id)
+    if not millis then
+        return true
+    end
+    local other_millis, other_seq = parse_id(other)
+    return millis < other_millis or (millis == other_millis and seq < other_seq)
+end
+
+local event = cjson.decode(ARGV[2])
+local target_id = event['target_id']
+-- 只有 <millis> 的 ID 会匹配同一毫秒内的所有消息
+if not parse_id(target_id) then
+    return { 'not_found' }
+end
+local message
+local state = redis.call('HGET', KEYS[3], target_id)
+if state then
+    message = cjson.decode(state)
+else
+    local entries = redis.call('XRANGE', KEYS[1], target_id, target_id)
+    if #entries == 0 then
+        return { 'not_found' }
+    end
+    -- 状态以 XRANGE 返回的 ID 为键, 与读取历史时一致
+    target_id = entries[1][1]
+    event['target_id'] = target_id
+    local fields = entries[1][2]
+    for i = 1, #fields, 2 do
+        if fields[i] == 'message' then
+            message = cjson.decode(fields[i + 1])
+        end
+    end
+end
+if not message or (message['kind'] or 'message') ~= 'message' then
+    return { 'not_found' }
+end
+if message['deleted'] then
+    return { 'deleted' }
+end
+
+local kind = event['kind']
+if kind ~= 'reaction' and event['username'] ~= message['username'] and ARGV[4] ~= '1' then
+    return { 'not_allowed' }
+end
+if kind == 'edit' then
+    message['content'] = event['content']
+    message['edited'] = true
+elseif kind == 'delete' then
+    message['content'] = ''
+    message['deleted'] = true
+    message['reactions'] = nil
+else
+    local reactions = message['reactions'] or {}
+    local usernames = reactions[event['content']] or {}
+    local index
+    for i, username in ipairs(usernames) do
+        if username == event['username'] then
+            index = i
+        end
+    end
+    if index then
+        table.remove(usernames, index)
+    else
+        table.insert(usernames, event['username'])
+    end
+    if #usernames == 0 then
+        reactions[event['content']] = nil
+    else
+        reactions[event['content']] = usernames
+    end
+    message['reactions'] = reactions
+    event['reactions'] = reactions
+end
+redis.call('HSET', KEYS[3], target_id, cjson.encode(message))
+
+local seq = redis.call('INCR', KEYS[2])
+event['seq'] = seq
+local id = redis.call('XADD', KEYS[1], 'MAXLEN', '~', ARGV[3], '*', 'message', cjson.encode(event))
+event['id'] = id
+redis.call('PUBLISH', ARGV[1], cjson.encode(event))
+
+-- 状态多于历史长度时, 删除已不在历史中的消息的状态
+if redis.call('HLEN', KEYS[3]) > tonumber(ARGV[3]) then
+    local first = redis.call('XRANGE', KEYS[1], '-', '+', 'COUNT', 1)
+    if #first > 0 then
+        for _, message_id in ipairs(redis.call('HKEYS', KEYS[3])) do
+            if is_before(message_id, first[1][1]) then
+                redis.call('HDEL', KEYS[3], message_id)
+            end
+        end
+    end
+end
+return { 'ok', cjson.encode(event) }
@@ -0,0 +1,726 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::chat_repository::{
+    ChannelMessage, ChatChannel, ChatRepository, FromChannelMessage, HistoryQuery, INBOX_MAX_LEN,
+    INBOX_TTL, Member, ModerationAction, RoomModeration, UpdateRejected, is_after, user_channel,
+};
+use crate::subscriber::{self, BufferOptions, SubscriberReceiver};
+
+/// 每个频道 broadcast 的缓冲区大小, 订阅者落后超过该数量时会丢消息, 按订阅者的溢出策略处理.
+const BROADCAST_CAPACITY: usize = 1024;
+
+/// 最多保存的离线用户收件箱数量. 满了之后先清理过期的, 仍然满时拒绝发给新的离线用户,
+/// 避免向任意用户名发私信使内存无限增长.
+const MAX_OFFLINE_INBOXES: usize = 10_000;
+
+/// 进程内的聊天仓库, 基于 tokio broadcast, 不依赖外部服务, 适合单节点运行和测试.
+#[derive(Clone)]
+pub struct MemoryRepository {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    /// 离线用户的收件箱, 以用户名为键. 加锁时先锁 `rooms`
+    inboxes: Arc<Mutex<HashMap<String, Inbox>>>,
+    history_max_len: usize,
+    max_inboxes: usize,
+}
+
+/// 用户离线期间收到的私信及最后一条的过期时间
+struct Inbox {
+    messages: VecDeque<ChannelMessage>,
+    expire_at: Instant,
+}
+
+struct Room {
+    sender: broadcast::Sender<ChannelMessage>,
+    history: VecDeque<ChannelMessage>,
+    last_id: (u64, u64),
+    seq: u64,
+    /// 在线连接及其过期时间
+    members: HashMap<Member, Instant>,
+    moderation: RoomModeration,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Room {
+            sender,
+            history: VecDeque::new(),
+            last_id: (0, 0),
+            seq: 0,
+            members: HashMap::new(),
+            moderation: RoomModeration::default(),
+        }
+    }
+
+    /// 生成与 Valkey stream 相同格式(`<millis>-<seq>`)且递增的消息 ID.
+    fn next_id(&mut self) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.last_id = if millis > self.last_id.0 {
+            (millis, 0)
+        } else {
+            (self.last_id.0, self.last_id.1 + 1)
+        };
+        format!("{}-{}", self.last_id.0, self.last_id.1)
+    }
+
+    /// 分配 ID 和序号后保存到历史并发送给订阅者, 返回有多少个订阅者.
+    fn publish(
+        &mut self,
+        message: &ChannelMessage,
+        history_max_len: usize,
+    ) -> (ChannelMessage, usize) {
+        self.seq += 1;
+        let message = ChannelMessage {
+            id: self.next_id(),
+            seq: self.seq,
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        self.history.push_back(message.clone());
+        while self.history.len() > history_max_len {
+            self.history.pop_front();
+        }
+        // 没有订阅者时 send 返回错误, 与 PUBLISH 一样视为 0 个订阅者
+        let receivers = self.sender.send(message.clone()).unwrap_or(0);
+        (message, receivers)
+    }
+}
+
+impl MemoryRepository {
+    /// `history_max_len` 为每个频道保留的历史消息数量.
+    pub fn new(history_max_len: usize) -> Self {
+        MemoryRepository {
+            rooms: Default::default(),
+            inboxes: Default::default(),
+            history_max_len,
+            max_inboxes: MAX_OFFLINE_INBOXES,
+        }
+    }
+}
+
+pub struct MemoryChannel {
+    channel: String,
+    repository: MemoryRepository,
+}
+
+#[async_trait]
+impl ChatChannel for MemoryChannel {
+    async fn publish(&mut self, message: &ChannelMessage) -> Result<usize> {
+        let mut rooms = self.repository.rooms.lock().unwrap();
+        let room = rooms.entry(self.channel.clone()).or_insert_with(Room::new);
+        let (_, receivers) = room.publish(message, self.repository.history_max_len);
+        Ok(receivers)
+    }
+
+    async fn notify(&mut self, message: &ChannelMessage) -> Result<usize> {
+        let mut rooms = self.repository.rooms.lock().unwrap();
+        let room = rooms.entry(self.channel.clone()).or_insert_with(Room::new);
+        let message = ChannelMessage {
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        Ok(room.sender.send(message).unwrap_or(0))
+    }
+
+    /// 直接修改历史中的目标消息, 之后读取历史时即为最新状态.
+    async fn update(&mut self, event: &ChannelMessage, moderator: bool) -> Result<ChannelMessage> {
+        let mut rooms = self.repository.rooms.lock().unwrap();
+        let room = rooms.entry(self.channel.clone()).or_insert_with(Room::new);
+        let target = room
+            .history
+            .iter_mut()
+            .find(|message| message.id == event.target_id)
+            .ok_or(UpdateRejected::NotFound)?;
+        let event = target.apply(event, moderator)?;
+        let (event, _) = room.publish(&event, self.repository.history_max_len);
+        Ok(event)
+    }
+}
+
+#[async_trait]
+impl ChatRepository for MemoryRepository {
+    type Channel = MemoryChannel;
+
+    fn get_channel(&self, channel: &str) -> MemoryChannel {
+        MemoryChannel {
+            channel: channel.into(),
+            repository: self.clone(),
+        }
+    }
+
+    async fn history(&self, channel: &str, query: &HistoryQuery) -> Result<Vec<ChannelMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get(channel) else {
+            return Ok(vec![]);
+        };
+        let messages = match &query.since_id {
+            Some(since_id) => room
+                .history
+                .iter()
+                .filter(|m| is_after(&m.id, since_id))
+                .take(query.limit)
+                .cloned()
+                .collect(),
+            None => {
+                let skip = room.history.len().saturating_sub(query.limit);
+                room.history.iter().skip(skip).cloned().collect()
+            }
+        };
+        Ok(messages)
+    }
+
+    async fn join_member(
+        &self,
+        channel: &str,
+        member: &Member,
+        ttl: Duration,
+        exclusive: bool,
+    ) -> Result<Vec<Member>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(channel.into()).or_insert_with(Room::new);
+        let now = Instant::now();
+        room.members.retain(|_, expire_at| *expire_at > now);
+        let others: Vec<Member> = room
+            .members
+            .keys()
+            .filter(|m| m.username == member.username && m.session_id != member.session_id)
+            .cloned()
+            .collect();
+        if !exclusive || others.is_empty() {
+            room.members.insert(member.clone(), now + ttl);
+        }
+        Ok(others)
+    }
+
+    async fn add_member(&self, channel: &str, member: &Member, ttl: Duration) -> Result<()> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(channel.into())
+            .or_insert_with(Room::new)
+            .members
+            .insert(member.clone(), Instant::now() + ttl);
+        Ok(())
+    }
+
+    async fn remove_member(&self, channel: &str, member: &Member) -> Result<()> {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(channel) {
+            room.members.remove(member);
+        }
+        Ok(())
+    }
+
+    async fn members(&self, channel: &str) -> Result<Vec<String>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(channel) else {
+            return Ok(vec![]);
+        };
+        let now = Instant::now();
+        room.members.retain(|_, expire_at| *expire_at > now);
+        let usernames: BTreeSet<&String> = room.members.keys().map(|m| &m.username).collect();
+        Ok(usernames.into_iter().cloned().collect())
+    }
+
+    /// 接收者在线时发到其用户频道, 否则保存到收件箱. 只有订阅时才创建用户频道,
+    /// 没有订阅者的用户频道在这里移除.
+    async fn send_direct(&self, message: &ChannelMessage) -> Result<bool> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let message = ChannelMessage {
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        let channel = user_channel(&message.recipient);
+        if let Some(room) = rooms.get(&channel) {
+            if room.sender.send(message.clone()).is_ok() {
+                return Ok(true);
+            }
+            rooms.remove(&channel);
+        }
+
+        let mut inboxes = self.inboxes.lock().unwrap();
+        let now = Instant::now();
+        if !inboxes.contains_key(&message.recipient) && inboxes.len() >= self.max_inboxes {
+            inboxes.retain(|_, inbox| inbox.expire_at > now);
+            if inboxes.len() >= self.max_inboxes {
+                anyhow::bail!("too many offline inboxes, direct message dropped");
+            }
+        }
+        let inbox = inboxes
+            .entry(message.recipient.clone())
+            .or_insert_with(|| Inbox {
+                messages: VecDeque::new(),
+                expire_at: now,
+            });
+        if inbox.expire_at <= now {
+            inbox.messages.clear();
+        }
+        inbox.messages.push_back(message);
+        while inbox.messages.len() > INBOX_MAX_LEN {
+            inbox.messages.pop_front();
+        }
+        inbox.expire_at = now + INBOX_TTL;
+        Ok(false)
+    }
+
+    async fn take_inbox(&self, username: &str) -> Result<Vec<ChannelMessage>> {
+        match self.inboxes.lock().unwrap().remove(username) {
+            Some(inbox) if inbox.expire_at > Instant::now() => Ok(inbox.messages.into()),
+            _ => Ok(vec![]),
+        }
+    }
+
+    async fn room_moderation(&self, channel: &str) -> Result<RoomModeration> {
+        let rooms = self.rooms.lock().unwrap();
+        Ok(rooms
+            .get(channel)
+            .map(|room| room.moderation.clone())
+            .unwrap_or_default())
+    }
+
+    async fn claim_room(&self, channel: &str, username: &str) -> Result<RoomModeration> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(channel.into()).or_insert_with(Room::new);
+        if room.moderation.owner.is_empty() {
+            room.moderation.owner = username.into();
+        }
+        Ok(room.moderation.clone())
+    }
+
+    async fn moderate(
+        &self,
+        channel: &str,
+        action: ModerationAction,
+        username: &str,
+    ) -> Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let moderation = &mut rooms
+            .entry(channel.into())
+            .or_insert_with(Room::new)
+            .moderation;
+        match action {
+            ModerationAction::Kick => {}
+            ModerationAction::Ban => {
+                moderation.banned.insert(username.into());
+            }
+            ModerationAction::Unban => {
+                moderation.banned.remove(username);
+            }
+            ModerationAction::Mute => {
+                moderation.muted.insert(username.into());
+            }
+            ModerationAction::Unmute => {
+                moderation.muted.remove(username);
+            }
+            ModerationAction::Op => {
+                moderation.moderators.insert(username.into());
+            }
+            ModerationAction::Deop => {
+                moderation.moderators.remove(username);
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn subscribe<T>(
+        &self,
+        channel: &str,
+        buffer: BufferOptions,
+        token: CancellationToken,
+    ) -> Result<SubscriberReceiver<T>>
+    where
+        T: FromChannelMessage,
+    {
+        let mut receiver = self
+            .rooms
+            .lock()
+            .unwrap()
+            .entry(channel.into())
+            .or_insert_with(Room::new)
+            .sender
+            .subscribe();
+        let (tx, rx) = subscriber::buffer(buffer);
+
+        debug!(channel, "subscribed to channel");
+
+        let channel = channel.to_string();
+        let poll_message_task = async move {
+            loop {
+                tokio::select! {
+                    message = receiver.recv() => {
+                        match message {
+                            Ok(message) => {
+                                if !tx.send(T::from(Ok(message))) {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(skipped)) => {
+                                warn!(channel, skipped, "subscriber lagged");
+                                if !tx.lagged(skipped) {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Closed) => {
+                                break;
+                            }
+                        }
+                    },
+                    _ = token.cancelled() => {
+                        break;
+                    },
+                }
+            }
+            debug!(channel, "unsubscribed from channel");
+        };
+        tokio::spawn(poll_message_task);
+
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_repository::{MessageKind, RoomRole, is_stream_id};
+    use crate::subscriber::{OverflowPolicy, Overflowed};
+
+    fn message(content: &str) -> ChannelMessage {
+        ChannelMessage {
+            username: "tester".into(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_to_subscribers_in_order() {
+        let repository = MemoryRepository::new(10);
+        let token = CancellationToken::new();
+        let mut rx = repository
+            .subscribe::<Result<ChannelMessage>>("room", BufferOptions::default(), token.clone())
+            .await
+            .unwrap();
+
+        let mut channel = repository.get_channel("room");
+        assert_eq!(channel.publish(&message("a")).await.unwrap(), 1);
+        assert_eq!(channel.publish(&message("b")).await.unwrap(), 1);
+
+        let a = rx.recv().await.unwrap().unwrap();
+        let b = rx.recv().await.unwrap().unwrap();
+        assert_eq!((a.content.as_str(), b.content.as_str()), ("a", "b"));
+        assert!(is_after(&b.id, &a.id));
+        assert_eq!((a.seq, b.seq), (1, 2));
+        assert!(a.at.is_some() && a.at <= b.at);
+
+        token.cancel();
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged_subscriber_follows_overflow_policy() {
+        let repository = MemoryRepository::new(10);
+        let buffer = BufferOptions {
+            capacity: BROADCAST_CAPACITY * 2,
+            overflow: OverflowPolicy::Disconnect,
+        };
+        let mut rx = repository
+            .subscribe::<Result<ChannelMessage>>("room", buffer, CancellationToken::new())
+            .await
+            .unwrap();
+
+        // 转发任务还没有运行, broadcast 的接收者落后
+        let mut channel = repository.get_channel("room");
+        for _ in 0..=BROADCAST_CAPACITY {
+            channel.publish(&message("flood")).await.unwrap();
+        }
+
+        let err = rx.recv().await.unwrap().unwrap_err();
+        assert!(err.is::<Overflowed>(), "{err:?}");
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn history_keeps_latest_messages() {
+        let repository = MemoryRepository::new(3);
+        let mut channel = repository.get_channel("room");
+        for content in ["a", "b", "c", "d"] {
+            channel.publish(&message(content)).await.unwrap();
+        }
+
+        let contents = |messages: Vec<ChannelMessage>| {
+            messages.into_iter().map(|m| m.content).collect::<Vec<_>>()
+        };
+        let latest = repository
+            .history("room", &HistoryQuery::latest(2))
+            .await
+            .unwrap();
+        assert_eq!(contents(latest.clone()), ["c", "d"]);
+
+        let all = repository
+            .history("room", &HistoryQuery::latest(10))
+            .await
+            .unwrap();
+        assert_eq!(contents(all.clone()), ["b", "c", "d"]);
+
+        let since = repository
+            .history("room", &HistoryQuery::since(&all[0].id, 10))
+            .await
+            .unwrap();
+        assert_eq!(contents(since), ["c", "d"]);
+        assert!(is_after(&latest[1].id, &latest[0].id));
+    }
+
+    #[tokio::test]
+    async fn members_expire_without_heartbeat() {
+        let repository = MemoryRepository::new(10);
+        let alice = Member::new("alice");
+        let bob = Member::new("bob");
+        let ttl = Duration::from_secs(60);
+        repository.add_member("room", &alice, ttl).await.unwrap();
+        repository
+            .add_member("room", &Member::new("alice"), ttl)
+            .await
+            .unwrap();
+        repository
+            .add_member("room", &bob, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(repository.members("room").await.unwrap(), ["alice"]);
+
+        repository.add_member("room", &bob, ttl).await.unwrap();
+        assert_eq!(repository.members("room").await.unwrap(), ["alice", "bob"]);
+
+        repository.remove_member("room", &bob).await.unwrap();
+        assert_eq!(repository.members("room").await.unwrap(), ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn history_returns_latest_state_of_updated_messages() {
+        let repository = MemoryRepository::new(10);
+        let mut channel = repository.get_channel("room");
+        channel.publish(&message("hello")).await.unwrap();
+        let history = repository
+            .history("room", &HistoryQuery::latest(1))
+            .await
+            .unwrap();
+        let event = |kind, username: &str, content: &str| ChannelMessage {
+            kind,
+            username: username.into(),
+            content: content.into(),
+            target_id: history[0].id.clone(),
+            ..Default::default()
+        };
+
+        let edited = channel
+            .update(&event(MessageKind::Edit, "tester", "hello, world"), false)
+            .await
+            .unwrap();
+        assert!(is_after(&edited.id, &history[0].id));
+        let err = channel
+            .update(&event(MessageKind::Edit, "other", "hijacked"), false)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UpdateRejected>(),
+            Some(&UpdateRejected::NotAllowed)
+        );
+        for username in ["alice", "bob", "alice"] {
+            channel
+                .update(&event(MessageKind::Reaction, username, "+1"), false)
+                .await
+                .unwrap();
+        }
+
+        let latest = repository
+            .history("room", &HistoryQuery::latest(10))
+            .await
+            .unwrap();
+        assert_eq!(latest.len(), 5);
+        assert_eq!(latest[0].content, "hello, world");
+        assert!(latest[0].edited);
+        assert_eq!(latest[0].reactions["+1"], ["bob"]);
+
+        channel
+            .update(&event(MessageKind::Delete, "moderator", ""), true)
+            .await
+            .unwrap();
+        let err = channel
+            .update(&event(MessageKind::Reaction, "alice", "+1"), false)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UpdateRejected>(),
+            Some(&UpdateRejected::Deleted)
+        );
+    }
+
+    #[tokio::test]
+    async fn update_requires_full_message_id() {
+        let repository = MemoryRepository::new(10);
+        let mut channel = repository.get_channel("room");
+        channel.publish(&message("hello")).await.unwrap();
+        let history = repository
+            .history("room", &HistoryQuery::latest(1))
+            .await
+            .unwrap();
+        let (millis, _) = history[0].id.split_once('-').unwrap();
+        assert!(!is_stream_id(millis));
+
+        let edit = ChannelMessage {
+            kind: MessageKind::Edit,
+            username: "tester".into(),
+            content: "edited".into(),
+            target_id: millis.into(),
+            ..Default::default()
+        };
+        let err = channel.update(&edit, false).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UpdateRejected>(),
+            Some(&UpdateRejected::NotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_offline_user_waits_in_inbox() {
+        let repository = MemoryRepository::new(10);
+        let direct = |content: &str| ChannelMessage {
+            kind: MessageKind::Direct,
+            recipient: "bob".into(),
+            ..message(content)
+        };
+        assert!(!repository.send_direct(&direct("a")).await.unwrap());
+        assert!(!repository.send_direct(&direct("b")).await.unwrap());
+
+        let token = CancellationToken::new();
+        let mut rx = repository
+            .subscribe::<Result<ChannelMessage>>(
+                &user_channel("bob"),
+                BufferOptions::default(),
+                token.clone(),
+            )
+            .await
+            .unwrap();
+        let inbox = repository.take_inbox("bob").await.unwrap();
+        let contents: Vec<&str> = inbox.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["a", "b"]);
+        assert!(repository.take_inbox("bob").await.unwrap().is_empty());
+
+        assert!(repository.send_direct(&direct("c")).await.unwrap());
+        assert_eq!(rx.recv().await.unwrap().unwrap().content, "c");
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn offline_inboxes_are_capped() {
+        let repository = MemoryRepository {
+            max_inboxes: 2,
+            ..MemoryRepository::new(10)
+        };
+        let direct = |recipient: &str| ChannelMessage {
+            kind: MessageKind::Direct,
+            username: "alice".into(),
+            recipient: recipient.into(),
+            content: "hi".into(),
+            ..Default::default()
+        };
+        for recipient in ["bob", "carol", "bob"] {
+            assert!(!repository.send_direct(&direct(recipient)).await.unwrap());
+        }
+        assert!(repository.send_direct(&direct("dave")).await.is_err());
+        // 私信不会创建用户频道
+        assert!(repository.rooms.lock().unwrap().is_empty());
+
+        // 过期的收件箱被清理后可以发给新的离线用户
+        repository
+            .inboxes
+            .lock()
+            .unwrap()
+            .get_mut("carol")
+            .unwrap()
+            .expire_at = Instant::now();
+        assert!(!repository.send_direct(&direct("dave")).await.unwrap());
+        assert!(repository.take_inbox("carol").await.unwrap().is_empty());
+        assert_eq!(repository.take_inbox("bob").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn first_user_owns_room_and_moderates_it() {
+        let repository = MemoryRepository::new(10);
+        let moderation = repository.claim_room("room", "alice").await.unwrap();
+        assert_eq!(moderation.owner, "alice");
+        let moderation = repository.claim_room("room", "bob").await.unwrap();
+        assert_eq!(moderation.owner, "alice");
+
+        for (action, username) in [
+            (ModerationAction::Op, "bob"),
+            (ModerationAction::Ban, "carol"),
+            (ModerationAction::Mute, "dave"),
+            (ModerationAction::Kick, "erin"),
+        ] {
+            repository.moderate("room", action, username).await.unwrap();
+        }
+        let moderation = repository.room_moderation("room").await.unwrap();
+        assert_eq!(moderation.role("alice", false), RoomRole::Owner);
+        assert_eq!(moderation.role("bob", false), RoomRole::Moderator);
+        assert_eq!(moderation.role("bob", true), RoomRole::Admin);
+        assert!(moderation.banned.contains("carol"));
+        assert!(moderation.muted.contains("dave"));
+
+        let (owner, bob, member) = (RoomRole::Owner, RoomRole::Moderator, RoomRole::Member);
+        assert!(ModerationAction::Ban.permits(bob, member));
+        assert!(!ModerationAction::Ban.permits(bob, bob));
+        assert!(!ModerationAction::Ban.permits(bob, owner));
+        assert!(!ModerationAction::Op.permits(bob, member));
+        assert!(ModerationAction::Deop.permits(owner, bob));
+
+        repository
+            .moderate("room", ModerationAction::Unban, "carol")
+            .await
+            .unwrap();
+        let moderation = repository.room_moderation("room").await.unwrap();
+        assert!(moderation.banned.is_empty());
+        assert!(
+            repository
+                .room_moderation("other")
+                .await
+                .unwrap()
+                .owner
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn join_member_reports_other_sessions() {
+        let repository = MemoryRepository::new(10);
+        let ttl = Duration::from_secs(60);
+        let first = Member::new("alice");
+        let second = Member::new("alice");
+
+        let others = repository.join_member("room", &first, ttl, true).await;
+        assert!(others.unwrap().is_empty());
+
+        let others = repository.join_member("room", &second, ttl, true).await;
+        assert_eq!(others.unwrap(), vec![first.clone()]);
+        assert_eq!(repository.members("room").await.unwrap(), ["alice"]);
+
+        let others = repository.join_member("room", &second, ttl, false).await;
+        assert_eq!(others.unwrap(), [first]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+use std::future::{Future, ready};
+use std::sync::Mutex;
+
+use axum::{Router, routing::get};
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+/// 超出 [`ChatMetrics`] 聊天室数量上限的聊天室共用的标签值.
+pub const OTHER_ROOMS: &str = "_other";
+
+/// 安装 Prometheus recorder, 之后 `metrics` 宏记录的指标都由它导出.
+pub fn setup_metrics_recorder() -> PrometheusHandle {
+    const PUBLISH_SECONDS: &[f64] = &[
+        0.000_5, 0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 3.0,
+    ];
+
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("instant_chat_publish_duration_seconds".to_string()),
+            PUBLISH_SECONDS,
+        )
+        .unwrap()
+        .install_recorder()
+        .unwrap()
+}
+
+/// 在单独的 HTTP 端口上提供 `/metrics`, `shutdown` 完成后退出.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    handle: PrometheusHandle,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), std::io::Error> {
+    let app = Router::new().route("/metrics", get(move || ready(handle.render())));
+    debug!(
+        "metrics server listening on {}",
+        listener.local_addr().unwrap()
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// 聊天服务的连接和消息指标. 按聊天室区分的指标最多使用 `room_cap` 个聊天室名作为标签,
+/// 之后出现的聊天室统计到 [`OTHER_ROOMS`], 避免标签基数随聊天室数量无限增长.
+pub struct ChatMetrics {
+    room_cap: usize,
+    state: Mutex<MetricsState>,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    /// 已作为标签使用的聊天室名
+    labeled: HashSet<String>,
+    /// 本服务实例中每个聊天室加入的连接数
+    sessions: HashMap<String, usize>,
+}
+
+impl ChatMetrics {
+    pub fn new(room_cap: usize) -> Self {
+        ChatMetrics {
+            room_cap,
+            state: Default::default(),
+        }
+    }
+
+    /// 聊天室的标签值, 已标记的聊天室保持不变, 超出上限后返回 [`OTHER_ROOMS`].
+    pub fn room_label(&self, room: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        Self::label(&mut state.labeled, self.room_cap, room)
+    }
+
+    fn label(labeled: &mut HashSet<String>, cap: usize, room: &str) -> String {
+        if labeled.contains(room) {
+            return room.into();
+        }
+        if labeled.len() < cap {
+            labeled.insert(room.into());
+            return room.into();
+        }
+        OTHER_ROOMS.into()
+    }
+
+    pub fn session_started(&self) {
+        gauge!("instant_chat_connected_users").increment(1);
+    }
+
+    pub fn session_ended(&self) {
+        gauge!("instant_chat_connected_users").decrement(1);
+    }
+
+    pub fn room_joined(&self, room: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.sessions.entry(room.into()).or_default() += 1;
+        let label = Self::label(&mut state.labeled, self.room_cap, room);
+        gauge!("instant_chat_room_users", "room" => label).increment(1);
+        gauge!("instant_chat_rooms").set(state.sessions.len() as f64);
+    }
+
+    pub fn room_left(&self, room: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(sessions) = state.sessions.get_mut(room) {
+            *sessions -= 1;
+            if *sessions == 0 {
+                state.sessions.remove(room);
+            }
+        }
+        let label = Self::label(&mut state.labeled, self.room_cap, room);
+        gauge!("instant_chat_room_users", "room" => label).decrement(1);
+        gauge!("instant_chat_rooms").set(state.sessions.len() as f64);
+    }
+
+    pub fn message_published(&self, room: &str) {
+        let label = self.room_label(room);
+        counter!("instant_chat_messages_total", "room" => label).increment(1);
+    }
+
+    pub fn message_rejected(&self, reason: &'static str) {
+        counter!("instant_chat_rejected_messages_total", "reason" => reason).increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_labels_are_capped() {
+        let metrics = ChatMetrics::new(2);
+        assert_eq!(metrics.room_label("a"), "a");
+        assert_eq!(metrics.room_label("b"), "b");
+        assert_eq!(metrics.room_label("c"), OTHER_ROOMS);
+        assert_eq!(metrics.room_label("a"), "a");
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// 令牌桶限制: 每秒补充 `rate` 个令牌, 最多积攒 `burst` 个.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// `rate` 为 0 时不限制.
+    pub fn new(rate: f64, burst: u32) -> Option<Self> {
+        (rate > 0.0).then(|| RateLimit {
+            rate,
+            burst: burst.max(1),
+        })
+    }
+
+    /// 令牌桶从空到满需要的时间.
+    pub fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst as f64 / self.rate)
+    }
+}
+
+/// 超出限制时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RateLimitAction {
+    /// 丢弃消息, 给发送者回复错误消息
+    #[default]
+    Drop,
+    /// 以 RESOURCE_EXHAUSTED 结束连接
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitOptions {
+    /// 每个用户的限制, 同一用户的多个连接共享
+    pub per_user: Option<RateLimit>,
+    /// 每个聊天室的限制, 聊天室中所有用户共享
+    pub per_room: Option<RateLimit>,
+    pub action: RateLimitAction,
+}
+
+/// 令牌桶的存储, 在进程内或 Valkey 中, 后者在多个服务实例之间共享限制.
+#[async_trait]
+pub trait RateLimiter: Send + Sync + 'static {
+    /// 从 `key` 的令牌桶中取一个令牌, 返回是否允许.
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<bool>;
+}
+
+struct TokenBucket {
+    tokens: f64,
+    at: Instant,
+}
+
+impl TokenBucket {
+    fn acquire(&mut self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 桶数量超过该值时清理已经补满的桶, 避免内存随用户数增长.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// 进程内的令牌桶, 每个服务实例分别限制.
+#[derive(Default)]
+pub struct LocalRateLimiter {
+    buckets: Mutex<HashMap<String, (TokenBucket, RateLimit)>>,
+}
+
+#[async_trait]
+impl RateLimiter for LocalRateLimiter {
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<bool> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, (bucket, limit)| now - bucket.at < limit.refill_time());
+        }
+        let (bucket, _) = buckets.entry(key.into()).or_insert_with(|| {
+            let bucket = TokenBucket {
+                tokens: limit.burst as f64,
+                at: now,
+            };
+            (bucket, limit)
+        });
+        Ok(bucket.acquire(limit, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let limit = RateLimit::new(2.0, 3).unwrap();
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 3.0,
+            at: start,
+        };
+        let allowed = (0..4).filter(|_| bucket.acquire(limit, start)).count();
+        assert_eq!(allowed, 3);
+
+        // 2 个令牌每秒, 半秒后补充 1 个
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.acquire(limit, later));
+        assert!(!bucket.acquire(limit, later));
+
+        // 长时间空闲也最多积攒 burst 个
+        let idle = later + Duration::from_secs(60);
+        let allowed = (0..5).filter(|_| bucket.acquire(limit, idle)).count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn zero_rate_disables_limit() {
+        assert_eq!(RateLimit::new(0.0, 10), None);
+    }
+
+    #[tokio::test]
+    async fn local_rate_limiter_keeps_keys_apart() {
+        let limiter = LocalRateLimiter::default();
+        let limit = RateLimit::new(0.001, 1).unwrap();
+        assert!(limiter.acquire("user:alice", limit).await.unwrap());
+        assert!(!limiter.acquire("user:alice", limit).await.unwrap());
+        assert!(limiter.acquire("user:bob", limit).await.unwrap());
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use futures::Stream;
+use metrics::counter;
+use tokio::sync::Notify;
+
+use crate::chat_repository::FromChannelMessage;
+
+/// 订阅者缓冲区满时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// 丢弃缓冲区中最旧的消息
+    #[default]
+    DropOldest,
+    /// 丢弃新到的消息
+    DropNewest,
+    /// 断开订阅者, 订阅者收到一个错误后结束
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// 每个订阅者的缓冲区大小和溢出策略.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferOptions {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        BufferOptions {
+            capacity: 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// 缓冲区溢出后断开订阅者时, 订阅者收到的错误.
+#[derive(Debug)]
+pub struct Overflowed {
+    pub capacity: usize,
+}
+
+impl fmt::Display for Overflowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "subscriber buffer overflowed, {} messages pending",
+            self.capacity
+        )
+    }
+}
+
+impl std::error::Error for Overflowed {}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    options: BufferOptions,
+}
+
+impl<T> Shared<T> {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+/// 有界的订阅缓冲区, 发送端不会阻塞, 按 [`OverflowPolicy`] 处理消费慢的订阅者,
+/// 避免一个卡住的客户端让服务端内存无限增长.
+pub fn buffer<T: FromChannelMessage>(
+    options: BufferOptions,
+) -> (SubscriberSender<T>, SubscriberReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(options.capacity.min(64)),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        options: BufferOptions {
+            capacity: options.capacity.max(1),
+            ..options
+        },
+    });
+    (
+        SubscriberSender {
+            shared: shared.clone(),
+        },
+        SubscriberReceiver { shared },
+    )
+}
+
+pub struct SubscriberSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: FromChannelMessage> SubscriberSender<T> {
+    /// 放入缓冲区, 缓冲区已满时按溢出策略处理. 接收端已关闭或因溢出断开时返回 false.
+    pub fn send(&self, item: T) -> bool {
+        let options = self.shared.options;
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.buffer.len() >= options.capacity {
+            let policy = options.overflow;
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    state.buffer.pop_front();
+                    state.buffer.push_back(item);
+                    counter!("instant_chat_dropped_messages_total", "policy" => policy.as_str())
+                        .increment(1);
+                }
+                OverflowPolicy::DropNewest => {
+                    counter!("instant_chat_dropped_messages_total", "policy" => policy.as_str())
+                        .increment(1);
+                    return true;
+                }
+                OverflowPolicy::Disconnect => {
+                    self.disconnect(state, 1);
+                    return false;
+                }
+            }
+        } else {
+            state.buffer.push_back(item);
+        }
+        drop(state);
+        self.shared.notify.notify_one();
+        true
+    }
+
+    /// 上游已经丢失了 `skipped` 条消息, 例如进程内 broadcast 的接收者落后, 按溢出策略处理:
+    /// 丢弃策略只计数, 断开策略断开订阅者. 接收端已关闭或因此断开时返回 false.
+    pub fn lagged(&self, skipped: u64) -> bool {
+        let policy = self.shared.options.overflow;
+        let state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if policy == OverflowPolicy::Disconnect {
+            self.disconnect(state, skipped);
+            return false;
+        }
+        counter!("instant_chat_dropped_messages_total", "policy" => policy.as_str())
+            .increment(skipped);
+        true
+    }
+
+    /// 丢弃缓冲区中的消息, 留下 [`Overflowed`] 错误后关闭, `lost` 为缓冲区之外丢失的消息数.
+    fn disconnect(&self, mut state: MutexGuard<'_, State<T>>, lost: u64) {
+        let policy = self.shared.options.overflow;
+        let dropped = state.buffer.len() as u64 + lost;
+        counter!("instant_chat_dropped_messages_total", "policy" => policy.as_str())
+            .increment(dropped);
+        state.buffer.clear();
+        let err = Overflowed {
+            capacity: self.shared.options.capacity,
+        };
+        state.buffer.push_back(T::from(Err(err.into())));
+        state.closed = true;
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl<T> SubscriberSender<T> {
+    /// 接收端是否已关闭, 关闭后发送端可以停止转发.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+impl<T> Drop for SubscriberSender<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+pub struct SubscriberReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> SubscriberReceiver<T> {
+    /// 接收下一条消息, 缓冲区为空且发送端已关闭时返回 None.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.buffer.pop_front() {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // notify_one 在没有等待者时保留一个通知, 检查与等待之间不会丢失
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// 转换为 [`Stream`], 便于与其他订阅合并.
+    pub fn into_stream(self) -> impl Stream<Item = T> {
+        futures::stream::unfold(self, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })
+    }
+}
+
+impl<T> Drop for SubscriberReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_repository::ChannelMessage;
+    use anyhow::Result;
+
+    fn message(content: &str) -> Result<ChannelMessage> {
+        Ok(ChannelMessage {
+            content: content.into(),
+            ..Default::default()
+        })
+    }
+
+    async fn drain(rx: &mut SubscriberReceiver<Result<ChannelMessage>>) -> Vec<String> {
+        let mut contents = vec![];
+        while let Some(message) = rx.recv().await {
+            contents.push(match message {
+                Ok(message) => message.content,
+                Err(err) => err.to_string(),
+            });
+        }
+        contents
+    }
+
+    fn options(overflow: OverflowPolicy) -> BufferOptions {
+        BufferOptions {
+            capacity: 2,
+            overflow,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_latest_messages() {
+        let (tx, mut rx) = buffer(options(OverflowPolicy::DropOldest));
+        for content in ["a", "b", "c"] {
+            assert!(tx.send(message(content)));
+        }
+        drop(tx);
+        assert_eq!(drain(&mut rx).await, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_earliest_messages() {
+        let (tx, mut rx) = buffer(options(OverflowPolicy::DropNewest));
+        for content in ["a", "b", "c"] {
+            assert!(tx.send(message(content)));
+        }
+        drop(tx);
+        assert_eq!(drain(&mut rx).await, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_ends_with_error() {
+        let (tx, mut rx) = buffer(options(OverflowPolicy::Disconnect));
+        assert!(tx.send(message("a")));
+        assert!(tx.send(message("b")));
+        assert!(!tx.send(message("c")));
+        assert!(tx.is_closed());
+
+        let err = rx.recv().await.unwrap().unwrap_err();
+        assert!(err.downcast_ref::<Overflowed>().is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged_follows_overflow_policy() {
+        let (tx, mut rx) = buffer(options(OverflowPolicy::DropOldest));
+        assert!(tx.send(message("a")));
+        assert!(tx.lagged(3));
+        assert!(tx.send(message("b")));
+        drop(tx);
+        assert_eq!(drain(&mut rx).await, ["a", "b"]);
+
+        let (tx, mut rx) = buffer(options(OverflowPolicy::Disconnect));
+        assert!(tx.send(message("a")));
+        assert!(!tx.lagged(3));
+        assert!(tx.is_closed());
+        let err = rx.recv().await.unwrap().unwrap_err();
+        assert!(err.downcast_ref::<Overflowed>().is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn receiver_wakes_on_send() {
+        let (tx, mut rx) = buffer::<Result<ChannelMessage>>(BufferOptions::default());
+        let receive = tokio::spawn(async move { rx.recv().await.map(|m| m.unwrap().content) });
+        tokio::task::yield_now().await;
+        assert!(tx.send(message("a")));
+        assert_eq!(receive.await.unwrap().as_deref(), Some("a"));
+        assert!(tx.is_closed());
+    }
+}
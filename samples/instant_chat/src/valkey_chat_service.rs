@@ -1,21 +1,100 @@
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use crate::auth::AuthenticatedUser;
+use crate::chat_repository::{
+    ChannelMessage, ChatChannel, ChatRepository, HistoryQuery, Member, MessageKind,
+    ModerationAction, RoomModeration, RoomRole, SubscriptionState, USER_CHANNEL_PREFIX,
+    UpdateRejected, is_after, is_stream_id, is_unavailable, user_channel,
+};
+use crate::health::HealthProbe;
+use crate::observability::ChatMetrics;
+use crate::rate_limit::{LocalRateLimiter, RateLimitAction, RateLimitOptions, RateLimiter};
 use crate::stub::instant_chat_server::InstantChat;
-use crate::stub::{ClientMessage, ServerMessage, Type};
-use crate::valkey_repository::{ChannelMessage, FromChannelMessage, ValkeyRepository};
+use crate::stub::{
+    ClientMessage, HistoryRequest, HistoryResponse, ListMembersRequest, ListMembersResponse,
+    Reaction, ServerMessage, Type,
+};
+use crate::subscriber::{BufferOptions, Overflowed, SubscriberReceiver};
+use crate::valkey_repository::ValkeyRepository;
 use anyhow::Result;
 use futures::Stream;
-use tokio::task;
-use tokio_stream::StreamExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+use tokio_stream::{StreamExt, StreamMap};
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tonic::metadata::MetadataMap;
 use tonic::{Request, Status, Streaming};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
-#[allow(dead_code)]
-pub struct ValkeyChatService {
+pub struct ValkeyChatService<R = ValkeyRepository> {
+    /// 取消后进入关闭流程: 拒绝新连接, 通知并断开所有连接
     shutdown: CancellationToken,
-    repository: ValkeyRepository,
+    /// 连接的收尾任务, 关闭时等待它们发布离开消息
+    tasks: TaskTracker,
+    repository: Arc<R>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    metrics: Arc<ChatMetrics>,
+    options: ChatOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatOptions {
+    /// 每个聊天室历史 stream 保留的大致消息数量
+    pub history_max_len: usize,
+    /// 新连接的用户先收到的最近消息数量, 0 表示不回放
+    pub history_replay: usize,
+    /// 在线状态的有效期, 连接每隔 1/3 有效期续期一次, 服务端崩溃后超时的用户会被移除
+    pub presence_ttl: Duration,
+    /// 同一用户重复登录同一聊天室时的处理方式, 多个服务实例共享 Valkey 时同样有效
+    pub duplicate_login: DuplicateLoginPolicy,
+    /// 每个连接的订阅缓冲区, 客户端接收慢时按溢出策略丢弃消息或断开
+    pub subscriber_buffer: BufferOptions,
+    /// 发送消息的频率限制
+    pub rate_limit: RateLimitOptions,
+    /// 按聊天室区分的指标最多使用的聊天室数量, 超出的聊天室合并统计
+    pub metrics_room_cap: usize,
+    /// 一个连接最多同时加入的聊天室数量, 包括 metadata 中的聊天室
+    pub max_rooms_per_session: usize,
+    /// 服务端管理员的用户名, 可以编辑和删除任何人的消息, 在所有聊天室中执行管理操作
+    pub moderators: HashSet<String>,
+}
+
+/// 回应最多的字符数
+const MAX_REACTION_LEN: usize = 16;
+
+/// 每个连接在每个聊天室中转发输入状态和已读回执的最小间隔, 更频繁的直接丢弃
+const EPHEMERAL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 同一用户名重复连接同一聊天室时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateLoginPolicy {
+    /// 允许多个连接同时在线
+    Allow,
+    /// 拒绝新连接, 返回 ALREADY_EXISTS
+    Reject,
+    /// 断开旧连接, 旧连接收到断开事件
+    #[default]
+    Kick,
+}
+
+impl Default for ChatOptions {
+    fn default() -> Self {
+        ChatOptions {
+            history_max_len: 1000,
+            history_replay: 50,
+            presence_ttl: Duration::from_secs(30),
+            duplicate_login: DuplicateLoginPolicy::default(),
+            subscriber_buffer: BufferOptions::default(),
+            rate_limit: RateLimitOptions::default(),
+            metrics_room_cap: 100,
+            max_rooms_per_session: 16,
+            moderators: HashSet::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -24,84 +103,1084 @@ struct ChatMetadata {
     chatroom: String,
 }
 
+fn metadata_value(m: &MetadataMap, key: &str) -> Result<String, Status> {
+    m.get(key)
+        .ok_or(Status::invalid_argument(format!("no {key} in metadata")))
+        .and_then(|value| {
+            value.to_str().map(|str| str.to_owned()).map_err(|_| {
+                Status::invalid_argument(format!("failed to get {key}(string) from metadata"))
+            })
+        })
+}
+
+/// 聊天室名不能为空, 也不能占用用户的私信频道. 后端的键以 `:` 分隔, 聊天室名不能包含 `:`,
+/// 否则会和其他聊天室或限流的键重叠.
+fn validate_chatroom(chatroom: &str) -> Result<(), Status> {
+    if chatroom.is_empty() {
+        return Err(Status::invalid_argument("chatroom must not be empty"));
+    }
+    if chatroom.contains(':') {
+        return Err(Status::invalid_argument("chatroom must not contain ':'"));
+    }
+    if chatroom.starts_with(USER_CHANNEL_PREFIX) {
+        return Err(Status::invalid_argument(format!(
+            "chatroom must not start with {USER_CHANNEL_PREFIX}"
+        )));
+    }
+    Ok(())
+}
+
+fn chatroom_value(m: &MetadataMap) -> Result<String, Status> {
+    let chatroom = metadata_value(m, "chatroom")?;
+    validate_chatroom(&chatroom)?;
+    Ok(chatroom)
+}
+
 impl TryFrom<&MetadataMap> for ChatMetadata {
     type Error = Status;
 
     fn try_from(m: &MetadataMap) -> std::result::Result<Self, Self::Error> {
-        let username = m
-            .get("username")
-            .ok_or(Status::invalid_argument("no username in metadata"))
-            .and_then(|username| {
-                username.to_str().map(|str| str.to_owned()).map_err(|_| {
-                    Status::invalid_argument("failed to get username(string) from metadata")
-                })
-            })?;
-        let chatroom = m
-            .get("chatroom")
-            .ok_or(Status::invalid_argument("no chatroom in metadata"))
-            .and_then(|username| {
-                username.to_str().map(|str| str.to_owned()).map_err(|_| {
-                    Status::invalid_argument("failed to get chatroom(string) from metadata")
-                })
-            })?;
+        let username = metadata_value(m, "username")?;
+        let chatroom = chatroom_value(m)?;
         Ok(ChatMetadata { username, chatroom })
     }
 }
 
+impl ChatMetadata {
+    fn from_request<T>(request: &Request<T>) -> Result<Self, Status> {
+        Ok(ChatMetadata {
+            username: request_username(request)?,
+            chatroom: chatroom_value(request.metadata())?,
+        })
+    }
+}
+
+/// 已认证的请求用户名取自认证信息, 否则取自 metadata.
+fn request_username<T>(request: &Request<T>) -> Result<String, Status> {
+    match request.extensions().get::<AuthenticatedUser>() {
+        Some(user) => Ok(user.username.clone()),
+        None => metadata_value(request.metadata(), "username"),
+    }
+}
+
 impl ValkeyChatService {
-    pub async fn new(valkey_url: &str, shutdown: CancellationToken) -> Result<Self> {
-        let repository = ValkeyRepository::new(valkey_url).await?;
-        let service = ValkeyChatService {
+    pub async fn new(
+        valkey_url: &str,
+        options: ChatOptions,
+        shutdown: CancellationToken,
+    ) -> Result<Self> {
+        let repository = ValkeyRepository::new(valkey_url, options.history_max_len).await?;
+        Ok(ValkeyChatService::with_repository(
+            repository, options, shutdown,
+        ))
+    }
+}
+
+impl<R: ChatRepository> ValkeyChatService<R> {
+    pub fn with_repository(
+        repository: R,
+        options: ChatOptions,
+        shutdown: CancellationToken,
+    ) -> Self {
+        ValkeyChatService {
             shutdown,
-            repository,
+            tasks: TaskTracker::new(),
+            repository: Arc::new(repository),
+            rate_limiter: Arc::new(LocalRateLimiter::default()),
+            metrics: Arc::new(ChatMetrics::new(options.metrics_room_cap)),
+            options,
+        }
+    }
+
+    /// 关闭服务用的句柄, 服务交给 gRPC server 之后仍然可以用它等待连接收尾.
+    pub fn drain_handle(&self) -> Drain {
+        Drain {
+            shutdown: self.shutdown.clone(),
+            tasks: self.tasks.clone(),
+        }
+    }
+
+    /// 健康检查用的句柄, 与 [`Self::drain_handle`] 一样在服务交给 gRPC server 之后使用.
+    pub fn health_probe(&self) -> HealthProbe<R> {
+        HealthProbe::new(self.repository.clone(), self.drain_handle())
+    }
+
+    /// 被聊天室封禁的用户不能读取聊天室的历史和成员. 读取管理信息出错时与发言检查一样放行.
+    async fn check_not_banned(&self, username: &str, room: &str) -> Result<(), Status> {
+        match self.repository.room_moderation(room).await {
+            Ok(moderation) if moderation.banned.contains(username) => {
+                Err(Status::permission_denied(format!(
+                    "user {username} is banned from chatroom {room}"
+                )))
+            }
+            Ok(_) => Ok(()),
+            Err(err) => {
+                warn!(
+                    ?err,
+                    chatroom = room,
+                    "failed to read room moderation, request allowed"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// 替换默认的进程内限流器, 例如使用 Valkey 在多个服务实例之间共享限制.
+    pub fn with_rate_limiter(self, rate_limiter: impl RateLimiter) -> Self {
+        ValkeyChatService {
+            rate_limiter: Arc::new(rate_limiter),
+            ..self
+        }
+    }
+
+    /// 订阅用户的私信频道并取出离线期间收到的私信. 先订阅再取收件箱, 之后的私信
+    /// 不会再进入收件箱.
+    async fn subscribe_direct(
+        &self,
+        username: &str,
+        chat_token: CancellationToken,
+    ) -> Result<
+        (
+            SubscriberReceiver<Result<ChannelMessage>>,
+            Vec<ChannelMessage>,
+        ),
+        Status,
+    > {
+        let rx = self
+            .repository
+            .subscribe::<Result<ChannelMessage>>(
+                &user_channel(username),
+                self.options.subscriber_buffer,
+                chat_token,
+            )
+            .await
+            .map_err(|err| backend_status("failed to subscribe direct messages", err))?;
+        let inbox = self
+            .repository
+            .take_inbox(username)
+            .await
+            .map_err(|err| backend_status("failed to read direct messages", err))?;
+        Ok((rx, inbox))
+    }
+}
+
+/// 服务的关闭流程, 见 [`ValkeyChatService::drain_handle`].
+#[derive(Clone)]
+pub struct Drain {
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Drain {
+    /// 是否已进入关闭流程.
+    pub fn is_draining(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// 等待进入关闭流程.
+    pub async fn draining(&self) {
+        self.shutdown.cancelled().await
+    }
+
+    /// 拒绝新的 Chat 调用, 向所有连接发送关闭事件并断开, 最多等待 `grace_period`
+    /// 让各连接发布离开消息. 全部完成返回 true, 超时返回 false.
+    pub async fn drain(&self, grace_period: Duration) -> bool {
+        self.shutdown.cancel();
+        self.tasks.close();
+        let drained = tokio::time::timeout(grace_period, self.tasks.wait())
+            .await
+            .is_ok();
+        if !drained {
+            warn!(
+                sessions = self.tasks.len(),
+                "grace period elapsed before all sessions finished"
+            );
+        }
+        drained
+    }
+}
+
+/// 后端暂时不可用时返回 UNAVAILABLE, 客户端可以稍后重试, 其他错误返回 INTERNAL.
+fn backend_status(context: &str, err: anyhow::Error) -> Status {
+    if is_unavailable(&err) {
+        Status::unavailable(format!("{context}: chat backend unavailable"))
+    } else {
+        Status::internal(format!("{context}: {err:?}"))
+    }
+}
+
+/// 按用户和聊天室的限制各取一个令牌, 超出时返回原因. 先检查用户, 被用户限制拒绝的消息
+/// 不占用聊天室的令牌, 私信没有聊天室, 只检查用户. 限流存储出错时放行, 不因此中断聊天.
+async fn check_rate_limit(
+    rate_limiter: &dyn RateLimiter,
+    options: &RateLimitOptions,
+    username: &str,
+    room: Option<&str>,
+) -> Option<&'static str> {
+    let mut checks = vec![(
+        options.per_user,
+        format!("user:{username}"),
+        "user rate limit exceeded",
+    )];
+    if let Some(room) = room {
+        checks.push((
+            options.per_room,
+            format!("room:{room}"),
+            "chatroom rate limit exceeded",
+        ));
+    }
+    for (limit, key, reason) in checks {
+        let Some(limit) = limit else { continue };
+        match rate_limiter.acquire(&key, limit).await {
+            Ok(true) => {}
+            Ok(false) => return Some(reason),
+            Err(err) => warn!(?err, key, "rate limiter failed, message allowed"),
+        }
+    }
+    None
+}
+
+/// 只发给本连接的错误消息.
+fn error_message(content: String) -> ServerMessage {
+    ServerMessage {
+        r#type: Type::Error.into(),
+        content,
+        ..Default::default()
+    }
+}
+
+/// 聊天室中的消息, 带上聊天室名.
+fn room_message(room: &str, message: ChannelMessage) -> ServerMessage {
+    ServerMessage {
+        room: room.into(),
+        ..ServerMessage::from(message)
+    }
+}
+
+/// 入站任务加入或离开聊天室后通知输出流.
+enum RoomEvent {
+    /// 已订阅聊天室, 先回放 `recent` 再转发 `live`
+    Joined {
+        room: String,
+        live: SubscriberReceiver<Result<ChannelMessage>>,
+        recent: Vec<ChannelMessage>,
+    },
+    /// 客户端离开了聊天室
+    Left { room: String },
+}
+
+type LiveStream = Pin<Box<dyn Stream<Item = Result<ChannelMessage>> + Send>>;
+
+/// 一个 Chat 调用的输出流.
+struct Session<R> {
+    repository: Arc<R>,
+    shutdown: CancellationToken,
+    member: Member,
+    /// 后端恢复后补齐消息时最多读取的历史数量
+    history_max_len: usize,
+    chat_token: CancellationToken,
+    /// 被其他连接踢出的聊天室交给入站任务清理
+    kicked: UnboundedSender<String>,
+}
+
+impl<R: ChatRepository> Session<R> {
+    /// 先发送离线私信, 再转发加入的各聊天室的消息(加入时先回放历史)、私信和只发给本连接的
+    /// `notices`. 收到踢出本连接的消息后, 发送断开事件并停止转发该聊天室, 收到踢出或封禁本用户的
+    /// 管理事件时同样停止; 订阅缓冲区溢出时以 DATA_LOSS 结束; `notices` 中的错误发送后同样结束.
+    /// 后端中断期间发送错误消息, 恢复后从历史补齐漏掉的消息. 服务关闭时发送关闭事件后结束, 入站任务结束后同样结束.
+    fn into_stream(
+        self,
+        inbox: Vec<ChannelMessage>,
+        mut direct: SubscriberReceiver<Result<ChannelMessage>>,
+        mut events: UnboundedReceiver<RoomEvent>,
+        mut notices: UnboundedReceiver<Result<ServerMessage, Status>>,
+    ) -> impl Stream<Item = Result<ServerMessage, Status>> {
+        async_stream::stream! {
+            let mut live: StreamMap<String, LiveStream> = StreamMap::new();
+            // 每个聊天室已发送的最后一条消息的 ID, 之后收到的不晚于它的消息已经发送过
+            let mut last_ids: HashMap<String, String> = HashMap::new();
+            for message in inbox {
+                yield Ok(ServerMessage::from(message));
+            }
+            loop {
+                // 优先发送 notices, 保证结束连接前的错误先于订阅结束送达
+                let (room, message) = tokio::select! {
+                    biased;
+                    Some(notice) = notices.recv() => {
+                        let end = notice.is_err();
+                        yield notice;
+                        if end {
+                            self.chat_token.cancel();
+                            break;
+                        }
+                        continue;
+                    },
+                    _ = self.shutdown.cancelled() => {
+                        yield Ok(ServerMessage {
+                            r#type: Type::Shutdown.into(),
+                            content: "server is shutting down".into(),
+                            ..Default::default()
+                        });
+                        break;
+                    },
+                    _ = self.chat_token.cancelled() => break,
+                    Some(event) = events.recv() => {
+                        match event {
+                            RoomEvent::Joined { room, live: rx, recent } => {
+                                if let Some(last) = recent.last() {
+                                    last_ids.insert(room.clone(), last.id.clone());
+                                }
+                                for message in recent {
+                                    yield Ok(room_message(&room, message));
+                                }
+                                live.insert(room, Box::pin(rx.into_stream()));
+                            }
+                            RoomEvent::Left { room } => {
+                                live.remove(&room);
+                                last_ids.remove(&room);
+                                yield Ok(ServerMessage {
+                                    r#type: Type::Disconnect.into(),
+                                    username: self.member.username.clone(),
+                                    room,
+                                    ..Default::default()
+                                });
+                            }
+                        }
+                        continue;
+                    },
+                    Some(message) = direct.recv() => match message {
+                        // 后端中断和恢复由聊天室的订阅处理, 私信不在历史中, 无需补齐
+                        Err(err) if err.is::<SubscriptionState>() => continue,
+                        message => (String::new(), message),
+                    },
+                    Some((room, message)) = live.next() => (room, message),
+                };
+                match message {
+                    Ok(message) if message.kind == MessageKind::Kick => {
+                        if message.kicks(&self.member) {
+                            live.remove(&room);
+                            last_ids.remove(&room);
+                            yield Ok(room_message(&room, message));
+                            let _ = self.kicked.send(room);
+                        }
+                    }
+                    Ok(message) => {
+                        // 没有 ID 的消息不在历史中, 总是发送
+                        if !message.id.is_empty() {
+                            if last_ids.get(&room).is_some_and(|last| !is_after(&message.id, last)) {
+                                continue;
+                            }
+                            last_ids.insert(room.clone(), message.id.clone());
+                        }
+                        // 被踢出或封禁时在管理事件之后发送断开事件, 停止转发该聊天室
+                        let removed = message.removes(&self.member).then(|| {
+                            let action = match message.moderation {
+                                Some(ModerationAction::Ban) => "banned",
+                                _ => "kicked",
+                            };
+                            ServerMessage {
+                                r#type: Type::Disconnect.into(),
+                                username: self.member.username.clone(),
+                                content: format!("{action} by {}", message.username),
+                                room: room.clone(),
+                                ..Default::default()
+                            }
+                        });
+                        yield Ok(room_message(&room, message));
+                        if let Some(disconnect) = removed {
+                            live.remove(&room);
+                            last_ids.remove(&room);
+                            yield Ok(disconnect);
+                            let _ = self.kicked.send(room);
+                        }
+                    }
+                    // 客户端接收太慢, 缓冲区溢出后断开
+                    Err(err) if err.is::<Overflowed>() => {
+                        yield Err(Status::data_loss(format!("{err}, client is too slow")));
+                        self.chat_token.cancel();
+                        break;
+                    }
+                    Err(err) => match err.downcast_ref::<SubscriptionState>() {
+                        Some(SubscriptionState::Interrupted) => {
+                            let notice = "chat backend unavailable, reconnecting";
+                            yield Ok(ServerMessage { room, ..error_message(notice.into()) });
+                        }
+                        Some(SubscriptionState::Resumed) => {
+                            let Some(since_id) = last_ids.get(&room).cloned() else { continue };
+                            let query = HistoryQuery::since(&since_id, self.history_max_len);
+                            match self.repository.history(&room, &query).await {
+                                Ok(missed) => {
+                                    for message in missed {
+                                        last_ids.insert(room.clone(), message.id.clone());
+                                        yield Ok(room_message(&room, message));
+                                    }
+                                }
+                                Err(err) => {
+                                    error!(?err, chatroom = &room, "failed to read missed messages");
+                                    let notice = "messages sent while the chat backend was unavailable may be missing";
+                                    yield Ok(ServerMessage { room, ..error_message(notice.into()) });
+                                }
+                            }
+                        }
+                        None => {
+                            yield Err(Status::data_loss(format!(
+                                "extract message from repository failed: {err}"
+                            )));
+                        }
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// 已加入的聊天室.
+struct JoinedRoom<C> {
+    channel: C,
+    /// 取消后停止订阅该聊天室
+    token: CancellationToken,
+    /// 上次转发输入状态和已读回执的时间
+    ephemeral_at: HashMap<MessageKind, Instant>,
+}
+
+/// 一个 Chat 调用的入站部分: 加入和离开聊天室, 发送消息, 维持在线状态.
+struct Inbound<R: ChatRepository> {
+    repository: Arc<R>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    metrics: Arc<ChatMetrics>,
+    options: ChatOptions,
+    member: Member,
+    /// metadata 中的聊天室, 消息没有指定聊天室时发到这里
+    default_room: String,
+    chat_token: CancellationToken,
+    /// 加入和离开聊天室后通知输出流
+    events: UnboundedSender<RoomEvent>,
+    notices: UnboundedSender<Result<ServerMessage, Status>>,
+    rooms: HashMap<String, JoinedRoom<R::Channel>>,
+}
+
+impl<R: ChatRepository> Inbound<R> {
+    /// 加入聊天室: 登记在线状态, 订阅并读取需要回放的最近消息, 按重复登录策略处理同一用户的
+    /// 其他连接, 然后广播加入. 已加入时什么也不做.
+    async fn join(&mut self, room: &str) -> Result<(), Status> {
+        validate_chatroom(room)?;
+        if self.rooms.contains_key(room) {
+            return Ok(());
+        }
+        let max_rooms = self.options.max_rooms_per_session;
+        if self.rooms.len() >= max_rooms {
+            return Err(Status::resource_exhausted(format!(
+                "at most {max_rooms} chatrooms per session"
+            )));
+        }
+        let moderation = self
+            .repository
+            .claim_room(room, &self.member.username)
+            .await
+            .map_err(|err| backend_status("failed to join chatroom", err))?;
+        if moderation.banned.contains(&self.member.username) {
+            return Err(Status::permission_denied(format!(
+                "user {} is banned from chatroom {room}",
+                &self.member.username
+            )));
+        }
+        let policy = self.options.duplicate_login;
+        let others = self
+            .repository
+            .join_member(
+                room,
+                &self.member,
+                self.options.presence_ttl,
+                policy == DuplicateLoginPolicy::Reject,
+            )
+            .await
+            .map_err(|err| backend_status("failed to join chatroom", err))?;
+        if policy == DuplicateLoginPolicy::Reject && !others.is_empty() {
+            return Err(Status::already_exists(format!(
+                "user {} is already in chatroom {room}",
+                &self.member.username
+            )));
+        }
+
+        let token = self.chat_token.child_token();
+        let (live, recent) = match self.subscribe(room, token.clone()).await {
+            Ok(subscribed) => subscribed,
+            Err(status) => {
+                token.cancel();
+                let _ = self.repository.remove_member(room, &self.member).await;
+                return Err(status);
+            }
+        };
+
+        let mut channel = self.repository.get_channel(room);
+        if policy == DuplicateLoginPolicy::Kick {
+            for other in others {
+                let kick_message = ChannelMessage {
+                    kind: MessageKind::Kick,
+                    username: other.username,
+                    content: "signed in from another session".into(),
+                    session_id: other.session_id,
+                    ..Default::default()
+                };
+                if let Err(err) = channel.notify(&kick_message).await {
+                    error!(
+                        ?err,
+                        username = &self.member.username,
+                        "failed to kick older session"
+                    );
+                }
+            }
+        }
+
+        let _ = self.events.send(RoomEvent::Joined {
+            room: room.into(),
+            live,
+            recent,
+        });
+        let connect_message = ChannelMessage {
+            kind: MessageKind::Connect,
+            username: self.member.username.clone(),
+            ..Default::default()
+        };
+        if let Err(err) = channel.publish(&connect_message).await {
+            error!(
+                ?err,
+                username = &self.member.username,
+                "failed to publish connect message"
+            );
+        }
+        debug!(
+            username = &self.member.username,
+            chatroom = room,
+            "user connected to chatroom"
+        );
+        self.metrics.room_joined(room);
+        self.rooms.insert(
+            room.into(),
+            JoinedRoom {
+                channel,
+                token,
+                ephemeral_at: HashMap::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// 订阅聊天室并读取需要回放的最近消息. 先订阅再读历史, 两者重叠的消息由
+    /// [`Session::into_stream`] 去重, 不会遗漏.
+    async fn subscribe(
+        &self,
+        room: &str,
+        token: CancellationToken,
+    ) -> Result<
+        (
+            SubscriberReceiver<Result<ChannelMessage>>,
+            Vec<ChannelMessage>,
+        ),
+        Status,
+    > {
+        let rx = self
+            .repository
+            .subscribe::<Result<ChannelMessage>>(room, self.options.subscriber_buffer, token)
+            .await
+            .map_err(|err| backend_status("failed to subscribe", err))?;
+        if self.options.history_replay == 0 {
+            return Ok((rx, vec![]));
+        }
+        let recent = self
+            .repository
+            .history(room, &HistoryQuery::latest(self.options.history_replay))
+            .await
+            .map_err(|err| backend_status("failed to read history", err))?;
+        Ok((rx, recent))
+    }
+
+    /// 离开聊天室, 未加入时返回 false. 被新连接踢出时用户仍在聊天室中, `announce` 为 false,
+    /// 不广播离开.
+    async fn leave(&mut self, room: &str, announce: bool) -> bool {
+        let Some(mut joined) = self.rooms.remove(room) else {
+            return false;
+        };
+        joined.token.cancel();
+        self.metrics.room_left(room);
+        if let Err(err) = self.repository.remove_member(room, &self.member).await {
+            error!(
+                ?err,
+                username = &self.member.username,
+                "failed to remove presence"
+            );
+        }
+        if announce {
+            let disconnect_message = ChannelMessage {
+                kind: MessageKind::Disconnect,
+                username: self.member.username.clone(),
+                ..Default::default()
+            };
+            if let Err(err) = joined.channel.publish(&disconnect_message).await {
+                error!(
+                    ?err,
+                    username = &self.member.username,
+                    "failed to publish disconnect message"
+                );
+            }
+        }
+        debug!(
+            username = &self.member.username,
+            chatroom = room,
+            kicked = !announce,
+            "user disconnected from chatroom"
+        );
+        true
+    }
+
+    /// 连接结束时离开所有聊天室.
+    async fn leave_all(&mut self) {
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            self.leave(&room, true).await;
+        }
+    }
+
+    /// 为所有已加入的聊天室续期在线状态.
+    async fn refresh(&self) {
+        for room in self.rooms.keys() {
+            if let Err(err) = self
+                .repository
+                .add_member(room, &self.member, self.options.presence_ttl)
+                .await
+            {
+                error!(
+                    ?err,
+                    username = &self.member.username,
+                    chatroom = room,
+                    "failed to refresh presence"
+                );
+            }
+        }
+    }
+
+    /// 处理客户端发来的一条消息, 返回连接是否继续.
+    async fn handle(&mut self, req: ClientMessage) -> bool {
+        let room = match req.room.is_empty() {
+            true => self.default_room.clone(),
+            false => req.room.clone(),
+        };
+        match req.r#type() {
+            Type::Connect => {
+                if let Err(status) = self.join(&room).await {
+                    let reason = format!("failed to join chatroom: {}", status.message());
+                    self.notice(&room, reason);
+                }
+                true
+            }
+            Type::Disconnect => {
+                if self.leave(&room, true).await {
+                    let _ = self.events.send(RoomEvent::Left { room });
+                } else {
+                    self.notice(&room, "not in chatroom".into());
+                }
+                true
+            }
+            Type::Edit => self.update(&room, MessageKind::Edit, req).await,
+            Type::Delete => self.update(&room, MessageKind::Delete, req).await,
+            Type::Reaction => self.update(&room, MessageKind::Reaction, req).await,
+            Type::Typing => {
+                self.notify(&room, MessageKind::Typing, String::new()).await;
+                true
+            }
+            Type::Read => {
+                if is_stream_id(&req.target_id) {
+                    self.notify(&room, MessageKind::Read, req.target_id).await;
+                } else {
+                    self.notice(&room, "target_id is not a valid message id".into());
+                }
+                true
+            }
+            Type::Moderation => self.moderate(&room, req).await,
+            _ if !req.recipient.is_empty() => self.send_direct(req).await,
+            _ => self.publish(&room, req.content).await,
+        }
+    }
+
+    /// 发送消息到已加入的聊天室, 返回连接是否继续.
+    async fn publish(&mut self, room: &str, content: String) -> bool {
+        if !self.rooms.contains_key(room) {
+            self.notice(room, "not in chatroom, message not sent".into());
+            return true;
+        }
+        if self.speaker_role(room, "message not sent").await.is_none() {
+            return true;
+        }
+        if let Some(reason) = self.check_rate_limit(Some(room)).await {
+            return reject_message(&self.notices, self.options.rate_limit.action, reason);
+        }
+        let channel_message = ChannelMessage {
+            username: self.member.username.clone(),
+            content,
+            ..Default::default()
+        };
+        let joined = self.rooms.get_mut(room).expect("joined room");
+        match joined.channel.publish(&channel_message).await {
+            Ok(_) => self.metrics.message_published(room),
+            Err(err) => {
+                error!(
+                    ?err,
+                    username = &self.member.username,
+                    "failed to publish message"
+                );
+                self.metrics.message_rejected("publish_failed");
+                let reason = format!("{}, message not sent", publish_error(&err));
+                self.notice(room, reason);
+            }
+        }
+        true
+    }
+
+    /// 编辑、删除或回应已加入的聊天室中的消息, 返回连接是否继续.
+    async fn update(&mut self, room: &str, kind: MessageKind, req: ClientMessage) -> bool {
+        if !self.rooms.contains_key(room) {
+            self.notice(room, "not in chatroom, message not changed".into());
+            return true;
+        }
+        let invalid = match kind {
+            _ if !is_stream_id(&req.target_id) => {
+                Some("target_id is not a valid message id".to_string())
+            }
+            MessageKind::Edit if req.content.is_empty() => {
+                Some("content must not be empty".to_string())
+            }
+            MessageKind::Reaction
+                if req.content.is_empty() || req.content.chars().count() > MAX_REACTION_LEN =>
+            {
+                Some(format!(
+                    "reaction must be 1 to {MAX_REACTION_LEN} characters"
+                ))
+            }
+            _ => None,
+        };
+        if let Some(reason) = invalid {
+            self.notice(room, reason);
+            return true;
+        }
+        let Some(role) = self.speaker_role(room, "message not changed").await else {
+            return true;
+        };
+        if let Some(reason) = self.check_rate_limit(Some(room)).await {
+            return reject_message(&self.notices, self.options.rate_limit.action, reason);
+        }
+        let event = ChannelMessage {
+            kind,
+            username: self.member.username.clone(),
+            target_id: req.target_id,
+            content: match kind {
+                MessageKind::Delete => String::new(),
+                _ => req.content,
+            },
+            ..Default::default()
+        };
+        let joined = self.rooms.get_mut(room).expect("joined room");
+        match joined
+            .channel
+            .update(&event, role >= RoomRole::Moderator)
+            .await
+        {
+            Ok(_) => self.metrics.message_published(room),
+            Err(err) => match err.downcast_ref::<UpdateRejected>() {
+                Some(rejected) => self.notice(room, rejected.to_string()),
+                None => {
+                    error!(
+                        ?err,
+                        username = &self.member.username,
+                        "failed to update message"
+                    );
+                    self.metrics.message_rejected("publish_failed");
+                    let reason = format!("{}, message not changed", publish_error(&err));
+                    self.notice(room, reason);
+                }
+            },
+        }
+        true
+    }
+
+    /// 对聊天室中的用户执行管理操作, 修改管理信息后广播管理事件, 返回连接是否继续.
+    async fn moderate(&mut self, room: &str, req: ClientMessage) -> bool {
+        if !self.rooms.contains_key(room) {
+            self.notice(room, "not in chatroom".into());
+            return true;
+        }
+        let action = match ModerationAction::try_from(req.action()) {
+            Ok(action) => action,
+            Err(status) => {
+                self.notice(room, status.message().into());
+                return true;
+            }
+        };
+        if req.target_user.is_empty() {
+            self.notice(room, "target_user must not be empty".into());
+            return true;
+        }
+        let moderation = match self.repository.room_moderation(room).await {
+            Ok(moderation) => moderation,
+            Err(err) => {
+                error!(?err, chatroom = room, "failed to read room moderation");
+                self.notice(room, format!("{}, {action} failed", publish_error(&err)));
+                return true;
+            }
+        };
+        let actor = self.role(&moderation, &self.member.username);
+        let target = self.role(&moderation, &req.target_user);
+        if !action.permits(actor, target) {
+            let reason = format!("not allowed to {action} {}", req.target_user);
+            self.notice(room, reason);
+            return true;
+        }
+        if let Err(err) = self
+            .repository
+            .moderate(room, action, &req.target_user)
+            .await
+        {
+            error!(?err, chatroom = room, %action, "failed to moderate chatroom");
+            self.notice(room, format!("{}, {action} failed", publish_error(&err)));
+            return true;
+        }
+        let event = ChannelMessage {
+            kind: MessageKind::Moderation,
+            username: self.member.username.clone(),
+            content: req.content,
+            moderation: Some(action),
+            target_user: req.target_user,
+            ..Default::default()
+        };
+        let joined = self.rooms.get_mut(room).expect("joined room");
+        if let Err(err) = joined.channel.publish(&event).await {
+            error!(?err, chatroom = room, %action, "failed to publish moderation event");
+            // 管理信息已经修改, 只是其他人收不到事件
+            let reason = format!("{}, {action} not announced", publish_error(&err));
+            self.notice(room, reason);
+        }
+        debug!(
+            username = &self.member.username,
+            chatroom = room,
+            %action,
+            target_user = &event.target_user,
+            "user moderated chatroom"
+        );
+        true
+    }
+
+    /// 用户在聊天室中的角色.
+    fn role(&self, moderation: &RoomModeration, username: &str) -> RoomRole {
+        moderation.role(username, self.options.moderators.contains(username))
+    }
+
+    /// 检查本用户能否在聊天室中发言, 被禁言或封禁时提示客户端并返回 None, 否则返回用户的角色.
+    /// 读取管理信息出错时与限流一样按普通成员放行, 不因此中断聊天.
+    async fn speaker_role(&self, room: &str, dropped: &str) -> Option<RoomRole> {
+        let username = &self.member.username;
+        let moderation = match self.repository.room_moderation(room).await {
+            Ok(moderation) => moderation,
+            Err(err) => {
+                warn!(
+                    ?err,
+                    chatroom = room,
+                    "failed to read room moderation, message allowed"
+                );
+                return Some(RoomRole::Member);
+            }
+        };
+        if moderation.muted.contains(username) || moderation.banned.contains(username) {
+            self.metrics.message_rejected("muted");
+            self.notice(room, format!("muted in chatroom, {dropped}"));
+            return None;
+        }
+        Some(self.role(&moderation, username))
+    }
+
+    /// 广播输入状态或已读回执, 不保存到历史. 未加入聊天室、间隔小于 [`EPHEMERAL_INTERVAL`]
+    /// 或被禁言时直接丢弃, 不提示客户端.
+    async fn notify(&mut self, room: &str, kind: MessageKind, target_id: String) {
+        let Some(joined) = self.rooms.get_mut(room) else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(last) = joined.ephemeral_at.get(&kind)
+            && now.duration_since(*last) < EPHEMERAL_INTERVAL
+        {
+            return;
+        }
+        joined.ephemeral_at.insert(kind, now);
+        let username = &self.member.username;
+        match self.repository.room_moderation(room).await {
+            Ok(moderation)
+                if moderation.muted.contains(username) || moderation.banned.contains(username) =>
+            {
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => debug!(?err, chatroom = room, "failed to read room moderation"),
+        }
+        let Some(joined) = self.rooms.get_mut(room) else {
+            return;
+        };
+        let event = ChannelMessage {
+            kind,
+            username: self.member.username.clone(),
+            target_id,
+            ..Default::default()
         };
-        Ok(service)
+        if let Err(err) = joined.channel.notify(&event).await {
+            debug!(?err, ?kind, chatroom = room, "failed to notify chatroom");
+        }
+    }
+
+    /// 发送私信, 返回连接是否继续.
+    async fn send_direct(&mut self, req: ClientMessage) -> bool {
+        if req.content.is_empty() {
+            self.notice("", "content must not be empty, message not sent".into());
+            return true;
+        }
+        if req.recipient == self.member.username {
+            self.notice("", "can't send direct message to yourself".into());
+            return true;
+        }
+        if let Some(reason) = self.check_rate_limit(None).await {
+            return reject_message(&self.notices, self.options.rate_limit.action, reason);
+        }
+        let direct_message = ChannelMessage {
+            kind: MessageKind::Direct,
+            username: self.member.username.clone(),
+            recipient: req.recipient,
+            content: req.content,
+            ..Default::default()
+        };
+        if let Err(err) = self.repository.send_direct(&direct_message).await {
+            error!(
+                ?err,
+                username = &self.member.username,
+                "failed to send direct message"
+            );
+            self.metrics.message_rejected("publish_failed");
+            let reason = format!("{}, message not sent", publish_error(&err));
+            self.notice("", reason);
+        }
+        true
+    }
+
+    async fn check_rate_limit(&self, room: Option<&str>) -> Option<&'static str> {
+        let reason = check_rate_limit(
+            self.rate_limiter.as_ref(),
+            &self.options.rate_limit,
+            &self.member.username,
+            room,
+        )
+        .await;
+        if reason.is_some() {
+            self.metrics.message_rejected("rate_limit");
+        }
+        reason
+    }
+
+    /// 只发给本连接的错误消息, 与聊天室有关时带上聊天室名.
+    fn notice(&self, room: &str, content: String) {
+        let _ = self.notices.send(Ok(ServerMessage {
+            room: room.into(),
+            ..error_message(content)
+        }));
+    }
+}
+
+/// 发布失败时告诉客户端的原因.
+fn publish_error(err: &anyhow::Error) -> &'static str {
+    match is_unavailable(err) {
+        true => "chat backend unavailable",
+        false => "internal error",
+    }
+}
+
+/// 按策略处理超出限制的消息, 返回连接是否继续.
+fn reject_message(
+    notices: &UnboundedSender<Result<ServerMessage, Status>>,
+    action: RateLimitAction,
+    reason: &str,
+) -> bool {
+    match action {
+        RateLimitAction::Drop => {
+            let _ = notices.send(Ok(error_message(format!("{reason}, message dropped"))));
+            true
+        }
+        RateLimitAction::Disconnect => {
+            let _ = notices.send(Err(Status::resource_exhausted(reason)));
+            false
+        }
     }
 }
 
 #[tonic::async_trait]
-impl InstantChat for ValkeyChatService {
+impl<R: ChatRepository> InstantChat for ValkeyChatService<R> {
     type ChatStream = Pin<Box<dyn Stream<Item = Result<ServerMessage, Status>> + Send + 'static>>;
 
     async fn chat(
         &self,
         request: Request<Streaming<ClientMessage>>,
     ) -> Result<tonic::Response<Self::ChatStream>, tonic::Status> {
-        let meta: ChatMetadata = request.metadata().try_into()?;
+        if self.shutdown.is_cancelled() {
+            return Err(Status::unavailable("server is shutting down"));
+        }
+        let meta = ChatMetadata::from_request(&request)?;
+        let member = Member::new(&meta.username);
         let chat_token = self.shutdown.child_token();
 
-        // listen to chatroom channel
-        let rx = self
-            .repository
-            .subscribe::<Result<ServerMessage, Status>>(&meta.chatroom, chat_token.clone())
+        let (direct_rx, inbox) = self
+            .subscribe_direct(&meta.username, chat_token.clone())
             .await
-            .map_err(|err| tonic::Status::internal(format!("failed to subscribe: {err:?}")))?;
-        let output_stream = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+            .inspect_err(|_| chat_token.cancel())?;
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let (notices_tx, notices_rx) = mpsc::unbounded_channel();
+        let mut inbound = Inbound {
+            repository: self.repository.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            options: self.options.clone(),
+            member: member.clone(),
+            default_room: meta.chatroom.clone(),
+            chat_token: chat_token.clone(),
+            events: events_tx,
+            notices: notices_tx,
+            rooms: HashMap::new(),
+        };
+        if let Err(status) = inbound.join(&meta.chatroom).await {
+            chat_token.cancel();
+            return Err(status);
+        }
 
-        let mut inbound = request.into_inner();
-        let mut channel = self.repository.get_channel(&meta.chatroom);
+        let (kicked_tx, mut kicked_rx) = mpsc::unbounded_channel();
+        let session = Session {
+            repository: self.repository.clone(),
+            shutdown: self.shutdown.clone(),
+            member,
+            history_max_len: self.options.history_max_len,
+            chat_token: chat_token.clone(),
+            kicked: kicked_tx,
+        };
+        let output_stream = session.into_stream(inbox, direct_rx, events_rx, notices_rx);
+
+        let mut requests = request.into_inner();
+        let presence_ttl = self.options.presence_ttl;
+        let metrics = self.metrics.clone();
+        metrics.session_started();
         let handle_client_message_task = async move {
-            let connect_message = ChannelMessage {
-                username: "(System)".into(),
-                content: format!("user {} connected", &meta.username),
-            };
-            let _ = channel.publish(&connect_message).await;
-            debug!(
-                username = &meta.username,
-                chatroom = &meta.chatroom,
-                "user connected to chatroom"
-            );
+            let heartbeat_period = presence_ttl / 3;
+            let mut heartbeat =
+                tokio::time::interval_at(Instant::now() + heartbeat_period, heartbeat_period);
             loop {
                 tokio::select! {
-                    req = inbound.next() => {
-                         match req {
+                    req = requests.next() => {
+                        match req {
                             Some(Ok(req)) => {
-                            let channel_message = ChannelMessage {
-                                username: meta.username.clone(),
-                                content: req.content,
-                            };
-
-                            let _ = channel.publish(&channel_message).await;
+                                if !inbound.handle(req).await {
+                                    break;
+                                }
                             },
                             Some(Err(status)) => {
                                 error!(code = ?status.code(), message = ?status.message(), "user connection error");
@@ -112,6 +1191,14 @@ impl InstantChat for ValkeyChatService {
                             },
                         }
                     },
+                    _ = heartbeat.tick() => inbound.refresh().await,
+                    Some(room) = kicked_rx.recv() => {
+                        inbound.leave(&room, false).await;
+                        // 被踢出所有聊天室后结束, 与只加入一个聊天室时一致
+                        if inbound.rooms.is_empty() {
+                            break;
+                        }
+                    },
                     _ = chat_token.cancelled() => {
                         break;
                     },
@@ -119,34 +1206,138 @@ impl InstantChat for ValkeyChatService {
             }
 
             chat_token.cancel();
-            let disconnect_message = ChannelMessage {
-                username: "(System)".into(),
-                content: format!("user {} disconnected", &meta.username),
-            };
-            let _ = channel.publish(&disconnect_message).await;
-            debug!(
-                username = &meta.username,
-                chatroom = &meta.chatroom,
-                "user disconnected from chatroom"
-            );
+            metrics.session_ended();
+            inbound.leave_all().await;
         };
-        task::spawn(handle_client_message_task);
+        self.tasks.spawn(handle_client_message_task);
 
         Ok(tonic::Response::new(Box::pin(output_stream)))
     }
+
+    async fn history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<tonic::Response<HistoryResponse>, tonic::Status> {
+        let username = request_username(&request)?;
+        let request = request.into_inner();
+        validate_chatroom(&request.chatroom)?;
+        self.check_not_banned(&username, &request.chatroom).await?;
+        if !request.since_id.is_empty() && !is_stream_id(&request.since_id) {
+            return Err(Status::invalid_argument(
+                "since_id is not a valid message id",
+            ));
+        }
+        let limit = match request.limit as usize {
+            0 => self.options.history_replay.max(1),
+            limit => limit.min(self.options.history_max_len),
+        };
+        let query = match request.since_id.as_str() {
+            "" => HistoryQuery::latest(limit),
+            since_id => HistoryQuery::since(since_id, limit),
+        };
+        let messages = self
+            .repository
+            .history(&request.chatroom, &query)
+            .await
+            .map_err(|err| backend_status("failed to read history", err))?
+            .into_iter()
+            .map(|message| room_message(&request.chatroom, message))
+            .collect();
+        Ok(tonic::Response::new(HistoryResponse { messages }))
+    }
+
+    async fn list_members(
+        &self,
+        request: Request<ListMembersRequest>,
+    ) -> Result<tonic::Response<ListMembersResponse>, tonic::Status> {
+        let username = request_username(&request)?;
+        let request = request.into_inner();
+        validate_chatroom(&request.chatroom)?;
+        self.check_not_banned(&username, &request.chatroom).await?;
+        let usernames = self
+            .repository
+            .members(&request.chatroom)
+            .await
+            .map_err(|err| backend_status("failed to list members", err))?;
+        Ok(tonic::Response::new(ListMembersResponse { usernames }))
+    }
 }
 
-impl FromChannelMessage for Result<ServerMessage, Status> {
-    fn from(channel_message: Result<ChannelMessage>) -> Result<ServerMessage, Status> {
-        channel_message
-            .map(|m| ServerMessage {
-                r#type: Type::Message.into(),
-                username: m.username,
-                content: m.content,
-                at: None,
-            })
-            .map_err(|err| {
-                Status::data_loss(format!("extract message from repository failed: {err}"))
-            })
+impl From<ChannelMessage> for ServerMessage {
+    fn from(m: ChannelMessage) -> Self {
+        ServerMessage {
+            r#type: Type::from(m.kind).into(),
+            username: m.username,
+            content: m.content,
+            id: m.id,
+            seq: m.seq,
+            recipient: m.recipient,
+            room: String::new(),
+            target_id: m.target_id,
+            edited: m.edited,
+            deleted: m.deleted,
+            reactions: m
+                .reactions
+                .into_iter()
+                .map(|(emoji, usernames)| Reaction { emoji, usernames })
+                .collect(),
+            action: m
+                .moderation
+                .map(crate::stub::ModerationAction::from)
+                .unwrap_or_default()
+                .into(),
+            target_user: m.target_user,
+            at: m.at.map(|at| SystemTime::from(at).into()),
+        }
+    }
+}
+
+impl From<MessageKind> for Type {
+    fn from(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::Message => Type::Message,
+            MessageKind::Connect => Type::Connect,
+            MessageKind::Disconnect | MessageKind::Kick => Type::Disconnect,
+            MessageKind::Direct => Type::Direct,
+            MessageKind::Edit => Type::Edit,
+            MessageKind::Delete => Type::Delete,
+            MessageKind::Reaction => Type::Reaction,
+            MessageKind::Typing => Type::Typing,
+            MessageKind::Read => Type::Read,
+            MessageKind::Moderation => Type::Moderation,
+        }
+    }
+}
+
+impl From<ModerationAction> for crate::stub::ModerationAction {
+    fn from(action: ModerationAction) -> Self {
+        match action {
+            ModerationAction::Kick => crate::stub::ModerationAction::Kick,
+            ModerationAction::Ban => crate::stub::ModerationAction::Ban,
+            ModerationAction::Unban => crate::stub::ModerationAction::Unban,
+            ModerationAction::Mute => crate::stub::ModerationAction::Mute,
+            ModerationAction::Unmute => crate::stub::ModerationAction::Unmute,
+            ModerationAction::Op => crate::stub::ModerationAction::Op,
+            ModerationAction::Deop => crate::stub::ModerationAction::Deop,
+        }
+    }
+}
+
+impl TryFrom<crate::stub::ModerationAction> for ModerationAction {
+    type Error = Status;
+
+    fn try_from(action: crate::stub::ModerationAction) -> Result<Self, Self::Error> {
+        match action {
+            crate::stub::ModerationAction::Unspecified => {
+                Err(Status::invalid_argument("unknown moderation action"))
+            }
+            crate::stub::ModerationAction::Kick => Ok(ModerationAction::Kick),
+            crate::stub::ModerationAction::Ban => Ok(ModerationAction::Ban),
+            crate::stub::ModerationAction::Unban => Ok(ModerationAction::Unban),
+            crate::stub::ModerationAction::Mute => Ok(ModerationAction::Mute),
+            crate::stub::ModerationAction::Unmute => Ok(ModerationAction::Unmute),
+            crate::stub::ModerationAction::Op => Ok(ModerationAction::Op),
+            crate::stub::ModerationAction::Deop => Ok(ModerationAction::Deop),
+        }
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use metrics::counter;
+use redis::{
+    Client, Msg,
+    aio::{PubSubSink, PubSubStream},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::backoff::Backoff;
+use crate::chat_repository::{ChannelMessage, FromChannelMessage, SubscriptionState};
+use crate::subscriber::{self, BufferOptions, SubscriberReceiver};
+use crate::valkey_repository::backend_error;
+
+/// 送达本地订阅者的内容.
+enum Delivery {
+    Message(Box<Result<ChannelMessage, String>>),
+    State(SubscriptionState),
+}
+
+/// 转发给某个本地订阅者, 订阅者已关闭时返回 false.
+type Subscriber = Box<dyn Fn(&Delivery) -> bool + Send>;
+
+type Rooms = Arc<Mutex<HashMap<String, HashMap<u64, Subscriber>>>>;
+
+/// 每个服务进程共享一个 Pub/Sub 连接. 频道按本地订阅者计数, 第一个订阅者加入时 SUBSCRIBE,
+/// 最后一个离开时 UNSUBSCRIBE, 收到的消息在进程内分发给所有本地订阅者.
+///
+/// 连接断开后以指数退避重连, 重连后重新订阅所有频道. 断开和恢复时订阅者分别收到
+/// [`SubscriptionState::Interrupted`] 和 [`SubscriptionState::Resumed`].
+#[derive(Clone)]
+pub struct PubSubHub {
+    rooms: Rooms,
+    /// 断开期间为 None. 同时用于串行化订阅计数的变化, 保证 SUBSCRIBE/UNSUBSCRIBE 与计数一致
+    sink: Arc<tokio::sync::Mutex<Option<PubSubSink>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl PubSubHub {
+    pub async fn connect(client: &Client) -> Result<Self> {
+        let (sink, stream) = client.get_async_pubsub().await?.split();
+        let hub = PubSubHub {
+            rooms: Default::default(),
+            sink: Arc::new(tokio::sync::Mutex::new(Some(sink))),
+            next_id: Default::default(),
+        };
+        tokio::spawn(hub.clone().supervise(client.clone(), stream));
+        Ok(hub)
+    }
+
+    /// 订阅频道, token 取消后该订阅者离开. 连接断开期间订阅也会成功, 重连后生效.
+    pub async fn subscribe<T>(
+        &self,
+        channel: &str,
+        buffer: BufferOptions,
+        token: CancellationToken,
+    ) -> Result<SubscriberReceiver<T>>
+    where
+        T: FromChannelMessage,
+    {
+        let (tx, rx) = subscriber::buffer(buffer);
+        let subscriber: Subscriber = Box::new(move |delivery| {
+            let message = match delivery {
+                Delivery::Message(message) => (**message).clone().map_err(anyhow::Error::msg),
+                Delivery::State(state) => Err((*state).into()),
+            };
+            tx.send(T::from(message))
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut sink = self.sink.lock().await;
+        let first = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let subscribers = rooms.entry(channel.into()).or_default();
+            subscribers.insert(id, subscriber);
+            subscribers.len() == 1
+        };
+        if first && let Some(sink) = sink.as_mut() {
+            if let Err(err) = sink.subscribe(channel).await {
+                self.rooms.lock().unwrap().remove(channel);
+                return Err(backend_error(err));
+            }
+            debug!(channel, "subscribed to channel");
+        }
+        drop(sink);
+
+        let hub = self.clone();
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            token.cancelled().await;
+            hub.unsubscribe(&channel, id).await;
+        });
+        Ok(rx)
+    }
+
+    async fn unsubscribe(&self, channel: &str, id: u64) {
+        let mut sink = self.sink.lock().await;
+        let last = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let Some(subscribers) = rooms.get_mut(channel) else {
+                return;
+            };
+            subscribers.remove(&id);
+            let last = subscribers.is_empty();
+            if last {
+                rooms.remove(channel);
+            }
+            last
+        };
+        // 断开期间不需要 UNSUBSCRIBE, 重连时只订阅仍有订阅者的频道
+        if last && let Some(sink) = sink.as_mut() {
+            match sink.unsubscribe(channel).await {
+                Ok(()) => debug!(channel, "unsubscribed from channel"),
+                Err(err) => error!(?err, channel, "failed to unsubscribe from channel"),
+            }
+        }
+    }
+
+    /// 本进程订阅中的频道数量.
+    pub fn channels(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    fn deliver(&self, channel: &str, delivery: &Delivery) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(subscribers) = rooms.get(channel) {
+            // 已关闭的订阅者在 token 取消时才会离开, 这里只跳过
+            for subscriber in subscribers.values() {
+                subscriber(delivery);
+            }
+        }
+    }
+
+    fn deliver_all(&self, delivery: &Delivery) {
+        let rooms = self.rooms.lock().unwrap();
+        for subscriber in rooms.values().flat_map(|subscribers| subscribers.values()) {
+            subscriber(delivery);
+        }
+    }
+
+    /// 分发消息直到连接断开, 然后重连并重新订阅, 如此往复.
+    async fn supervise(self, client: Client, mut stream: PubSubStream) {
+        loop {
+            while let Some(message) = stream.next().await {
+                let delivery = Delivery::Message(Box::new(parse(&message)));
+                self.deliver(message.get_channel_name(), &delivery);
+            }
+            *self.sink.lock().await = None;
+            warn!("pub/sub connection lost, reconnecting");
+            self.deliver_all(&Delivery::State(SubscriptionState::Interrupted));
+
+            stream = self.reconnect(&client).await;
+            counter!("instant_chat_valkey_reconnects_total").increment(1);
+            info!(channels = self.channels(), "pub/sub connection restored");
+            self.deliver_all(&Delivery::State(SubscriptionState::Resumed));
+        }
+    }
+
+    async fn reconnect(&self, client: &Client) -> PubSubStream {
+        let mut backoff = Backoff::default();
+        loop {
+            match self.resubscribe(client).await {
+                Ok(stream) => return stream,
+                Err(err) => {
+                    let delay = backoff.next_delay();
+                    warn!(?err, ?delay, "failed to reconnect pub/sub");
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn resubscribe(&self, client: &Client) -> Result<PubSubStream> {
+        let (mut new_sink, stream) = client.get_async_pubsub().await?.split();
+        // 持有锁直到重新订阅完成, 期间加入或离开的订阅者等待, 不会遗漏频道
+        let mut sink = self.sink.lock().await;
+        let channels: Vec<String> = self.rooms.lock().unwrap().keys().cloned().collect();
+        for channel in &channels {
+            new_sink.subscribe(channel).await?;
+        }
+        *sink = Some(new_sink);
+        Ok(stream)
+    }
+}
+
+fn parse(message: &Msg) -> Result<ChannelMessage, String> {
+    let payload: String = message.get_payload().map_err(|err| err.to_string())?;
+    serde_json::from_str(&payload).map_err(|err| err.to_string())
+}
@@ -1,112 +1,557 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeSet,
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
-use futures::StreamExt;
-use redis::{AsyncTypedCommands, Client, aio::MultiplexedConnection};
-use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use async_trait::async_trait;
+use chrono::Utc;
+use metrics::{counter, histogram};
+use redis::{
+    RedisError, Script,
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    streams::StreamRangeReply,
+};
 use tokio_util::sync::CancellationToken;
-use tracing::debug;
 
-pub struct ValkeyRepository {
-    client: Client,
-    pub_conn: MultiplexedConnection,
-}
+use crate::chat_repository::{
+    BackendUnavailable, ChannelMessage, ChatChannel, ChatRepository, FromChannelMessage,
+    HistoryQuery, INBOX_MAX_LEN, INBOX_TTL, Member, MessageKind, ModerationAction, RoomModeration,
+    UpdateRejected, is_stream_id, user_channel,
+};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::subscriber::{BufferOptions, SubscriberReceiver};
+use crate::valkey_pubsub::PubSubHub;
+
+/// 频道消息历史保存在 `<channel>:history` stream 中, 每条记录的 field 名.
+const HISTORY_FIELD: &str = "message";
+
+static PUBLISH_SCRIPT: LazyLock<Script> =
+    LazyLock::new(|| Script::new(include_str!("lua/publish.lua")));
+static JOIN_MEMBER_SCRIPT: LazyLock<Script> =
+    LazyLock::new(|| Script::new(include_str!("lua/join_member.lua")));
+static SEND_DIRECT_SCRIPT: LazyLock<Script> =
+    LazyLock::new(|| Script::new(include_str!("lua/send_direct.lua")));
+static UPDATE_MESSAGE_SCRIPT: LazyLock<Script> =
+    LazyLock::new(|| Script::new(include_str!("lua/update_message.lua")));
+static RATE_LIMIT_SCRIPT: LazyLock<Script> =
+    LazyLock::new(|| Script::new(include_str!("lua/rate_limit.lua")));
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
-pub struct ChannelMessage {
-    pub username: String,
-    pub content: String,
+pub struct ValkeyRepository {
+    pubsub: PubSubHub,
+    pub_conn: ConnectionManager,
+    history_max_len: usize,
 }
 
 pub struct ChannelPublisher {
     channel: String,
-    pub_conn: MultiplexedConnection,
+    history_key: String,
+    seq_key: String,
+    state_key: String,
+    history_max_len: usize,
+    pub_conn: ConnectionManager,
 }
 
 impl ChannelPublisher {
-    fn new(pub_conn: MultiplexedConnection, channel: &str) -> Self {
+    fn new(pub_conn: ConnectionManager, channel: &str, history_max_len: usize) -> Self {
         ChannelPublisher {
             channel: channel.into(),
+            history_key: history_key(channel),
+            seq_key: format!("{channel}:seq"),
+            state_key: state_key(channel),
+            history_max_len,
             pub_conn,
         }
     }
-    pub async fn publish(&mut self, message: &ChannelMessage) -> Result<usize> {
-        let message = serde_json::to_string(message)?;
-        self.pub_conn
-            .publish(&self.channel, message)
+}
+
+#[async_trait]
+impl ChatChannel for ChannelPublisher {
+    /// 盖上时间戳, 由 Valkey 分配频道内递增的序号, 追加到频道历史 stream 后再发布到频道,
+    /// 返回有多少个订阅者.
+    async fn publish(&mut self, message: &ChannelMessage) -> Result<usize> {
+        let message = ChannelMessage {
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        let payload = serde_json::to_string(&message)?;
+        let start = Instant::now();
+        let (_id, _seq, receivers): (String, u64, usize) = PUBLISH_SCRIPT
+            .key(&self.history_key)
+            .key(&self.seq_key)
+            .arg(&self.channel)
+            .arg(payload)
+            .arg(self.history_max_len)
+            .invoke_async(&mut self.pub_conn)
+            .await
+            .map_err(count_error("publish"))?;
+        histogram!("instant_chat_publish_duration_seconds").record(start.elapsed().as_secs_f64());
+        Ok(receivers)
+    }
+
+    async fn notify(&mut self, message: &ChannelMessage) -> Result<usize> {
+        let message = ChannelMessage {
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        let payload = serde_json::to_string(&message)?;
+        let receivers = redis::cmd("PUBLISH")
+            .arg(&self.channel)
+            .arg(payload)
+            .query_async(&mut self.pub_conn)
+            .await
+            .map_err(count_error("notify"))?;
+        Ok(receivers)
+    }
+
+    async fn update(&mut self, event: &ChannelMessage, moderator: bool) -> Result<ChannelMessage> {
+        if !is_stream_id(&event.target_id) {
+            return Err(UpdateRejected::NotFound.into());
+        }
+        let event = ChannelMessage {
+            at: Some(Utc::now()),
+            ..event.clone()
+        };
+        let payload = serde_json::to_string(&event)?;
+        let reply: Vec<String> = UPDATE_MESSAGE_SCRIPT
+            .key(&self.history_key)
+            .key(&self.seq_key)
+            .key(&self.state_key)
+            .arg(&self.channel)
+            .arg(payload)
+            .arg(self.history_max_len)
+            .arg(if moderator { "1" } else { "0" })
+            .invoke_async(&mut self.pub_conn)
             .await
-            .map_err(anyhow::Error::new)
+            .map_err(count_error("update"))?;
+        match reply.as_slice() {
+            [status, event] if status == "ok" => Ok(serde_json::from_str(event)?),
+            [code] => match UpdateRejected::from_code(code) {
+                Some(rejected) => Err(rejected.into()),
+                None => Err(anyhow::format_err!("unexpected update result {code}")),
+            },
+            _ => Err(anyhow::format_err!("unexpected update result {reply:?}")),
+        }
+    }
+}
+
+/// 按操作统计 Valkey 命令失败次数, 并归类错误.
+fn count_error(op: &'static str) -> impl Fn(RedisError) -> anyhow::Error {
+    move |err| {
+        counter!("instant_chat_valkey_errors_total", "op" => op).increment(1);
+        backend_error(err)
     }
 }
 
-pub trait FromChannelMessage: Send + 'static {
-    fn from(message: Result<ChannelMessage>) -> Self;
+/// 连接断开, 拒绝连接或超时的错误标记为 [`BackendUnavailable`], 其他错误原样返回.
+pub(crate) fn backend_error(err: RedisError) -> anyhow::Error {
+    let unavailable = err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_timeout();
+    match unavailable {
+        true => anyhow::Error::new(err).context(BackendUnavailable),
+        false => err.into(),
+    }
+}
+
+fn history_key(channel: &str) -> String {
+    format!("{channel}:history")
+}
+
+/// 编辑、删除或回应过的消息的最新状态保存在 `<channel>:state` hash 中, 消息 ID -> 消息 JSON.
+fn state_key(channel: &str) -> String {
+    format!("{channel}:state")
+}
+
+/// 频道在线连接保存在 `<channel>:members` sorted set 中, score 为过期时间(毫秒).
+fn members_key(channel: &str) -> String {
+    format!("{channel}:members")
+}
+
+/// 用户的离线私信保存在 `<user channel>:inbox` list 中.
+fn inbox_key(username: &str) -> String {
+    format!("{}:inbox", user_channel(username))
+}
+
+/// 聊天室的所有者保存在 `<channel>:owner` 中.
+fn owner_key(channel: &str) -> String {
+    format!("{channel}:owner")
+}
+
+/// 版主、封禁和禁言名单分别保存在 `<channel>:moderators`, `<channel>:banned`,
+/// `<channel>:muted` set 中.
+fn moderation_set_key(channel: &str, set: &str) -> String {
+    format!("{channel}:{set}")
+}
+
+/// 在 pipeline 中读取聊天室的管理信息, 结果由 [`RoomModerationReply`] 接收.
+fn read_moderation(pipe: &mut redis::Pipeline, channel: &str) {
+    pipe.cmd("GET").arg(owner_key(channel));
+    for set in ["moderators", "banned", "muted"] {
+        pipe.cmd("SMEMBERS").arg(moderation_set_key(channel, set));
+    }
+}
+
+type RoomModerationReply = (
+    Option<String>,
+    BTreeSet<String>,
+    BTreeSet<String>,
+    BTreeSet<String>,
+);
+
+fn room_moderation(reply: RoomModerationReply) -> RoomModeration {
+    let (owner, moderators, banned, muted) = reply;
+    RoomModeration {
+        owner: owner.unwrap_or_default(),
+        moderators,
+        banned,
+        muted,
+    }
+}
+
+fn member_entry(member: &Member) -> String {
+    format!("{}/{}", member.session_id, member.username)
+}
+
+fn parse_member_entry(entry: &str) -> Option<Member> {
+    entry.split_once('/').map(|(session_id, username)| Member {
+        username: username.into(),
+        session_id: session_id.into(),
+    })
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 impl ValkeyRepository {
-    pub async fn new(url: &str) -> Result<Self> {
+    /// `history_max_len` 为每个频道历史 stream 保留的大致消息数量(XADD MAXLEN ~).
+    pub async fn new(url: &str, history_max_len: usize) -> Result<Self> {
         let client = redis::Client::open(url)?;
-        let pub_conn = client
-            .get_multiplexed_tokio_connection_with_response_timeouts(
-                Duration::from_secs(3),
-                Duration::from_secs(3),
-            )
-            .await?;
-        Ok(Self { client, pub_conn })
+        // 连接断开后在后台以指数退避重连, 期间的命令返回错误
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(Duration::from_secs(3))
+            .set_response_timeout(Duration::from_secs(3))
+            .set_max_delay(10_000);
+        let pub_conn = ConnectionManager::new_with_config(client.clone(), config).await?;
+        let pubsub = PubSubHub::connect(&client).await?;
+        Ok(Self {
+            pubsub,
+            pub_conn,
+            history_max_len,
+        })
     }
 
-    /// 发布消息到频道,返回有多少个订阅者.
-    pub fn get_channel(&self, channel: &str) -> ChannelPublisher {
+    /// 共享同一连接的限流器, 令牌桶保存在 Valkey 中.
+    pub fn rate_limiter(&self) -> ValkeyRateLimiter {
+        ValkeyRateLimiter {
+            conn: self.pub_conn.clone(),
+        }
+    }
+}
+
+/// 令牌桶保存在 `ratelimit:<key>` hash 中, 多个服务实例共享同一个限制.
+pub struct ValkeyRateLimiter {
+    conn: ConnectionManager,
+}
+
+#[async_trait]
+impl RateLimiter for ValkeyRateLimiter {
+    async fn acquire(&self, key: &str, limit: RateLimit) -> Result<bool> {
+        let mut conn = self.conn.clone();
+        let allowed: i64 = RATE_LIMIT_SCRIPT
+            .key(format!("ratelimit:{key}"))
+            .arg(unix_millis())
+            .arg(limit.rate)
+            .arg(limit.burst)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(count_error("rate_limit"))?;
+        Ok(allowed == 1)
+    }
+}
+
+#[async_trait]
+impl ChatRepository for ValkeyRepository {
+    type Channel = ChannelPublisher;
+
+    fn get_channel(&self, channel: &str) -> ChannelPublisher {
         let pub_conn = self.pub_conn.clone();
-        ChannelPublisher::new(pub_conn, channel)
+        ChannelPublisher::new(pub_conn, channel, self.history_max_len)
+    }
+
+    async fn history(&self, channel: &str, query: &HistoryQuery) -> Result<Vec<ChannelMessage>> {
+        let mut conn = self.pub_conn.clone();
+        let key = history_key(channel);
+        let reply: StreamRangeReply = match &query.since_id {
+            Some(since_id) => redis::cmd("XRANGE")
+                .arg(&key)
+                .arg(format!("({since_id}"))
+                .arg("+")
+                .arg("COUNT")
+                .arg(query.limit)
+                .query_async(&mut conn)
+                .await
+                .map_err(count_error("history"))?,
+            None => {
+                let mut reply: StreamRangeReply = redis::cmd("XREVRANGE")
+                    .arg(&key)
+                    .arg("+")
+                    .arg("-")
+                    .arg("COUNT")
+                    .arg(query.limit)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(count_error("history"))?;
+                reply.ids.reverse();
+                reply
+            }
+        };
+
+        let mut messages = reply
+            .ids
+            .into_iter()
+            .map(|entry| {
+                let payload: String = entry.get(HISTORY_FIELD).ok_or_else(|| {
+                    anyhow::format_err!("no {HISTORY_FIELD} in entry {}", entry.id)
+                })?;
+                let message: ChannelMessage = serde_json::from_str(&payload)?;
+                Ok(ChannelMessage {
+                    id: entry.id,
+                    ..message
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // 编辑、删除或回应过的消息替换为最新状态
+        let chat_messages: Vec<usize> = (0..messages.len())
+            .filter(|&i| messages[i].kind == MessageKind::Message)
+            .collect();
+        if chat_messages.is_empty() {
+            return Ok(messages);
+        }
+        let mut cmd = redis::cmd("HMGET");
+        cmd.arg(state_key(channel));
+        for &i in &chat_messages {
+            cmd.arg(&messages[i].id);
+        }
+        let states: Vec<Option<String>> = cmd
+            .query_async(&mut conn)
+            .await
+            .map_err(count_error("history"))?;
+        for (i, state) in chat_messages.into_iter().zip(states) {
+            if let Some(state) = state {
+                let latest: ChannelMessage = serde_json::from_str(&state)?;
+                messages[i] = ChannelMessage {
+                    id: std::mem::take(&mut messages[i].id),
+                    ..latest
+                };
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn join_member(
+        &self,
+        channel: &str,
+        member: &Member,
+        ttl: Duration,
+        exclusive: bool,
+    ) -> Result<Vec<Member>> {
+        let mut conn = self.pub_conn.clone();
+        let others: Vec<String> = JOIN_MEMBER_SCRIPT
+            .key(members_key(channel))
+            .arg(unix_millis())
+            .arg(ttl.as_millis() as u64)
+            .arg(member_entry(member))
+            .arg(&member.username)
+            .arg(if exclusive { "1" } else { "0" })
+            .invoke_async(&mut conn)
+            .await
+            .map_err(count_error("join_member"))?;
+        Ok(others
+            .iter()
+            .filter_map(|entry| parse_member_entry(entry))
+            .collect())
+    }
+
+    async fn add_member(&self, channel: &str, member: &Member, ttl: Duration) -> Result<()> {
+        let mut conn = self.pub_conn.clone();
+        let key = members_key(channel);
+        let ttl = ttl.as_millis() as u64;
+        redis::pipe()
+            .atomic()
+            .cmd("ZADD")
+            .arg(&key)
+            .arg(unix_millis() + ttl)
+            .arg(member_entry(member))
+            .ignore()
+            // 所有连接都离开或过期后, 整个 key 也随之过期
+            .cmd("PEXPIRE")
+            .arg(&key)
+            .arg(ttl)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(count_error("add_member"))?;
+        Ok(())
+    }
+
+    async fn remove_member(&self, channel: &str, member: &Member) -> Result<()> {
+        let mut conn = self.pub_conn.clone();
+        redis::cmd("ZREM")
+            .arg(members_key(channel))
+            .arg(member_entry(member))
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(count_error("remove_member"))?;
+        Ok(())
+    }
+
+    async fn members(&self, channel: &str) -> Result<Vec<String>> {
+        let mut conn = self.pub_conn.clone();
+        let key = members_key(channel);
+        let (entries,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(&key)
+            .arg("-inf")
+            .arg(unix_millis())
+            .ignore()
+            .cmd("ZRANGE")
+            .arg(&key)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await
+            .map_err(count_error("members"))?;
+        let usernames: BTreeSet<String> = entries
+            .into_iter()
+            .filter_map(|entry| {
+                entry
+                    .split_once('/')
+                    .map(|(_, username)| username.to_owned())
+            })
+            .collect();
+        Ok(usernames.into_iter().collect())
+    }
+
+    async fn send_direct(&self, message: &ChannelMessage) -> Result<bool> {
+        let mut conn = self.pub_conn.clone();
+        let message = ChannelMessage {
+            at: Some(Utc::now()),
+            ..message.clone()
+        };
+        let payload = serde_json::to_string(&message)?;
+        let receivers: usize = SEND_DIRECT_SCRIPT
+            .key(inbox_key(&message.recipient))
+            .arg(user_channel(&message.recipient))
+            .arg(payload)
+            .arg(INBOX_MAX_LEN)
+            .arg(INBOX_TTL.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(count_error("send_direct"))?;
+        Ok(receivers > 0)
+    }
+
+    async fn take_inbox(&self, username: &str) -> Result<Vec<ChannelMessage>> {
+        let mut conn = self.pub_conn.clone();
+        let key = inbox_key(username);
+        let (payloads,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .cmd("LRANGE")
+            .arg(&key)
+            .arg(0)
+            .arg(-1)
+            .cmd("DEL")
+            .arg(&key)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(count_error("take_inbox"))?;
+        payloads
+            .iter()
+            .map(|payload| Ok(serde_json::from_str(payload)?))
+            .collect()
     }
 
-    /// 订阅频道，返回一个 Receiver，外部用异步方式接收消息
-    pub async fn subscribe<T>(
+    async fn room_moderation(&self, channel: &str) -> Result<RoomModeration> {
+        let mut conn = self.pub_conn.clone();
+        let mut pipe = redis::pipe();
+        read_moderation(&mut pipe, channel);
+        let reply: RoomModerationReply = pipe
+            .query_async(&mut conn)
+            .await
+            .map_err(count_error("room_moderation"))?;
+        Ok(room_moderation(reply))
+    }
+
+    async fn claim_room(&self, channel: &str, username: &str) -> Result<RoomModeration> {
+        let mut conn = self.pub_conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("SET")
+            .arg(owner_key(channel))
+            .arg(username)
+            .arg("NX")
+            .ignore();
+        read_moderation(&mut pipe, channel);
+        let reply: RoomModerationReply = pipe
+            .query_async(&mut conn)
+            .await
+            .map_err(count_error("claim_room"))?;
+        Ok(room_moderation(reply))
+    }
+
+    async fn moderate(
         &self,
         channel: &str,
+        action: ModerationAction,
+        username: &str,
+    ) -> Result<()> {
+        let (cmd, set) = match action {
+            ModerationAction::Kick => return Ok(()),
+            ModerationAction::Ban => ("SADD", "banned"),
+            ModerationAction::Unban => ("SREM", "banned"),
+            ModerationAction::Mute => ("SADD", "muted"),
+            ModerationAction::Unmute => ("SREM", "muted"),
+            ModerationAction::Op => ("SADD", "moderators"),
+            ModerationAction::Deop => ("SREM", "moderators"),
+        };
+        let mut conn = self.pub_conn.clone();
+        redis::cmd(cmd)
+            .arg(moderation_set_key(channel, set))
+            .arg(username)
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(count_error("moderate"))?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let mut conn = self.pub_conn.clone();
+        redis::cmd("PING")
+            .query_async::<()>(&mut conn)
+            .await
+            .map_err(count_error("ping"))?;
+        Ok(())
+    }
+
+    async fn subscribe<T>(
+        &self,
+        channel: &str,
+        buffer: BufferOptions,
         token: CancellationToken,
-    ) -> Result<UnboundedReceiver<T>>
+    ) -> Result<SubscriberReceiver<T>>
     where
         T: FromChannelMessage,
     {
-        let mut pubsub = self.client.get_async_pubsub().await?;
-        pubsub.subscribe(channel).await?;
-        let (tx, rx) = mpsc::unbounded_channel();
-
-        debug!(channel, "subscribed to channel");
-
-        let channel = channel.to_string();
-        let poll_message_task = async move {
-            let mut on_message = pubsub.on_message();
-            loop {
-                tokio::select! {
-                    message = on_message.next() => {
-                        if let Some(message) = message {
-                            match message.get_payload::<String>() {
-                                Ok(payload) => {
-                                    let channel_message: Result<ChannelMessage> = serde_json::from_str(&payload)
-                                        .map_err(anyhow::Error::new);
-                                    let _ = tx.send(T::from(channel_message));
-                                }
-                                Err(err) => {
-                                    let _ = tx.send(T::from(Err(anyhow::Error::new(err))));
-                                }
-                            }
-                        } else {
-                            break;
-                        }
-                    },
-                    _ = token.cancelled() => {
-                        break;
-                    },
-                }
-            }
-            debug!(channel, "unsubscribed from channel");
-        };
-        tokio::spawn(poll_message_task);
-
-        Ok(rx)
+        self.pubsub.subscribe(channel, buffer, token).await
     }
 }
@@ -0,0 +1,459 @@
+//! Starts the chat server in-process on an ephemeral port with a self-signed TLS certificate
+//! and the in-memory backend, then talks to it through several gRPC clients.
+
+use std::time::Duration;
+
+use instant_chat::auth::Authenticator;
+use instant_chat::chat_repository::USER_CHANNEL_PREFIX;
+use instant_chat::memory_repository::MemoryRepository;
+use instant_chat::stub::{
+    ClientMessage, HistoryRequest, ListMembersRequest, ModerationAction, ServerMessage, Type,
+    instant_chat_client::InstantChatClient, instant_chat_server::InstantChatServer,
+};
+use instant_chat::valkey_chat_service::{ChatOptions, Drain, ValkeyChatService};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Status, Streaming};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestServer {
+    port: u16,
+    ca_pem: String,
+    drain: Drain,
+    stop: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// 由测试 CA 签发 `localhost` 的服务端证书, 返回 CA 证书和服务端身份
+fn tls_identity() -> (String, Identity) {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".into()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+    (
+        ca.pem(),
+        Identity::from_pem(cert.pem(), key.serialize_pem()),
+    )
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let (ca_pem, identity) = tls_identity();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = ValkeyChatService::with_repository(
+            MemoryRepository::new(100),
+            ChatOptions::default(),
+            CancellationToken::new(),
+        );
+        let drain = service.drain_handle();
+        let stop = CancellationToken::new();
+        let server = Server::builder()
+            .tls_config(ServerTlsConfig::new().identity(identity))
+            .unwrap()
+            .add_service(InstantChatServer::with_interceptor(
+                service,
+                Authenticator::disabled(),
+            ))
+            .serve_with_incoming_shutdown(
+                TcpListenerStream::new(listener),
+                stop.clone().cancelled_owned(),
+            );
+        let handle = tokio::spawn(async move { server.await.unwrap() });
+        TestServer {
+            port,
+            ca_pem,
+            drain,
+            stop,
+            handle,
+        }
+    }
+
+    async fn client(&self) -> InstantChatClient<Channel> {
+        let tls = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(&self.ca_pem))
+            .domain_name("localhost");
+        let channel = Channel::from_shared(format!("https://127.0.0.1:{}", self.port))
+            .unwrap()
+            .tls_config(tls)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        InstantChatClient::new(channel)
+    }
+
+    async fn stop(self) {
+        self.stop.cancel();
+        timeout(TIMEOUT, self.handle).await.unwrap().unwrap();
+    }
+}
+
+/// 一个用户的 Chat 流, 每个用户使用自己的客户端连接
+struct ChatUser {
+    client: InstantChatClient<Channel>,
+    tx: mpsc::Sender<ClientMessage>,
+    stream: Streaming<ServerMessage>,
+}
+
+impl ChatUser {
+    async fn send(&self, content: &str) {
+        let message = ClientMessage {
+            r#type: Type::Message.into(),
+            content: content.into(),
+            ..Default::default()
+        };
+        self.tx.send(message).await.unwrap();
+    }
+
+    async fn next(&mut self) -> ServerMessage {
+        timeout(TIMEOUT, self.stream.message())
+            .await
+            .expect("timed out waiting for message")
+            .expect("chat stream failed")
+            .expect("chat stream ended")
+    }
+
+    /// 读取消息直到聊天流结束, 返回结束前收到的消息
+    async fn until_end(&mut self) -> Vec<ServerMessage> {
+        let mut messages = vec![];
+        while let Some(message) = timeout(TIMEOUT, self.stream.message())
+            .await
+            .expect("timed out waiting for the chat stream to end")
+            .unwrap_or_default()
+        {
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+/// 以 `metadata` 中的键值打开 Chat 流
+async fn open_chat(
+    client: &mut InstantChatClient<Channel>,
+    metadata: &[(&'static str, &str)],
+) -> Result<ChatUser, Status> {
+    let (tx, rx) = mpsc::channel(32);
+    let mut request = Request::new(ReceiverStream::new(rx));
+    for (key, value) in metadata {
+        request.metadata_mut().insert(*key, value.parse().unwrap());
+    }
+    let stream = client.chat(request).await?.into_inner();
+    Ok(ChatUser {
+        client: client.clone(),
+        tx,
+        stream,
+    })
+}
+
+/// 以新的客户端连接加入聊天室, 跳过回放的历史, 直到收到自己加入的事件
+async fn join(server: &TestServer, username: &str, room: &str) -> ChatUser {
+    let mut client = server.client().await;
+    let mut user = open_chat(&mut client, &[("username", username), ("chatroom", room)])
+        .await
+        .unwrap();
+    loop {
+        let message = user.next().await;
+        if message.r#type() == Type::Connect && message.username == username {
+            assert_eq!(message.room, room);
+            return user;
+        }
+    }
+}
+
+fn assert_event(message: &ServerMessage, r#type: Type, username: &str) {
+    assert_eq!(
+        (message.r#type(), message.username.as_str()),
+        (r#type, username),
+        "{message:?}"
+    );
+}
+
+#[tokio::test]
+async fn join_and_leave_are_broadcast() {
+    let server = TestServer::start().await;
+
+    let mut alice = join(&server, "alice", "lobby").await;
+    let bob = join(&server, "bob", "lobby").await;
+    assert_event(&alice.next().await, Type::Connect, "bob");
+
+    // 关闭发送端即离开
+    drop(bob.tx);
+    let left = alice.next().await;
+    assert_event(&left, Type::Disconnect, "bob");
+    assert_eq!(left.room, "lobby");
+
+    // 别的聊天室的事件不会收到
+    let carol = join(&server, "carol", "kitchen").await;
+    alice.send("still here").await;
+    let message = alice.next().await;
+    assert_event(&message, Type::Message, "alice");
+    assert_eq!(message.content, "still here");
+
+    drop(carol);
+    drop(alice);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn messages_are_broadcast_in_order() {
+    let server = TestServer::start().await;
+
+    let mut alice = join(&server, "alice", "lobby").await;
+    let mut bob = join(&server, "bob", "lobby").await;
+    let mut carol = join(&server, "carol", "lobby").await;
+    assert_event(&alice.next().await, Type::Connect, "bob");
+    assert_event(&alice.next().await, Type::Connect, "carol");
+    assert_event(&bob.next().await, Type::Connect, "carol");
+
+    const COUNT: usize = 20;
+    let (alice_tx, bob_tx) = (alice.tx.clone(), bob.tx.clone());
+    let send = |tx: mpsc::Sender<ClientMessage>, username: &'static str| async move {
+        for i in 0..COUNT {
+            let message = ClientMessage {
+                r#type: Type::Message.into(),
+                content: format!("{username} {i}"),
+                ..Default::default()
+            };
+            tx.send(message).await.unwrap();
+        }
+    };
+    tokio::join!(send(alice_tx, "alice"), send(bob_tx, "bob"));
+
+    let mut received = vec![];
+    for user in [&mut alice, &mut bob, &mut carol] {
+        let mut messages = vec![];
+        for _ in 0..COUNT * 2 {
+            messages.push(user.next().await);
+        }
+        received.push(messages);
+    }
+
+    // 所有人收到的顺序相同, 序号连续递增, 每个发送者的消息保持发送顺序
+    for messages in &received {
+        let ids: Vec<_> = messages.iter().map(|message| &message.id).collect();
+        let expected: Vec<_> = received[0].iter().map(|message| &message.id).collect();
+        assert_eq!(ids, expected);
+    }
+    let messages = &received[0];
+    for pair in messages.windows(2) {
+        assert_eq!(pair[1].seq, pair[0].seq + 1);
+    }
+    for username in ["alice", "bob"] {
+        let contents: Vec<_> = messages
+            .iter()
+            .filter(|message| message.username == username)
+            .map(|message| message.content.clone())
+            .collect();
+        let expected: Vec<_> = (0..COUNT).map(|i| format!("{username} {i}")).collect();
+        assert_eq!(contents, expected);
+    }
+
+    drop((alice, bob, carol));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn invalid_metadata_is_rejected() {
+    let server = TestServer::start().await;
+    let mut client = server.client().await;
+
+    let private_room = format!("{USER_CHANNEL_PREFIX}alice");
+    let cases: [(&[(&'static str, &str)], &str); 5] = [
+        (&[("chatroom", "lobby")], "no username in metadata"),
+        (&[("username", "alice")], "no chatroom in metadata"),
+        (
+            &[("username", "alice"), ("chatroom", "")],
+            "chatroom must not be empty",
+        ),
+        (
+            &[("username", "alice"), ("chatroom", &private_room)],
+            "chatroom must not start with @",
+        ),
+        (
+            &[("username", "alice"), ("chatroom", "ratelimit:room:x")],
+            "chatroom must not contain ':'",
+        ),
+    ];
+    for (metadata, reason) in cases {
+        let Err(status) = open_chat(&mut client, metadata).await else {
+            panic!("chat with {metadata:?} should be rejected");
+        };
+        assert_eq!(status.code(), Code::InvalidArgument, "{metadata:?}");
+        assert_eq!(status.message(), reason);
+    }
+
+    // History 和 ListMembers 同样校验聊天室名, 不能读取用户的私信频道
+    for room in ["", private_room.as_str(), "lobby:history"] {
+        let (history, members) = read_room(&mut client, "alice", room).await;
+        assert_eq!(history.unwrap_err().code(), Code::InvalidArgument, "{room}");
+        assert_eq!(members.unwrap_err().code(), Code::InvalidArgument, "{room}");
+    }
+
+    // 被拒绝的请求不影响之后的连接
+    join(&server, "alice", "lobby").await;
+    server.stop().await;
+}
+
+fn with_username<T>(message: T, username: &str) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert("username", username.parse().unwrap());
+    request
+}
+
+/// 以 `username` 读取聊天室的历史和成员
+async fn read_room(
+    client: &mut InstantChatClient<Channel>,
+    username: &str,
+    room: &str,
+) -> (Result<(), Status>, Result<(), Status>) {
+    let history = HistoryRequest {
+        chatroom: room.into(),
+        ..Default::default()
+    };
+    let members = ListMembersRequest {
+        chatroom: room.into(),
+    };
+    (
+        client
+            .history(with_username(history, username))
+            .await
+            .map(|_| ()),
+        client
+            .list_members(with_username(members, username))
+            .await
+            .map(|_| ()),
+    )
+}
+
+#[tokio::test]
+async fn banned_user_cannot_read_and_muted_user_cannot_notify() {
+    let server = TestServer::start().await;
+
+    // 第一个加入的 alice 是聊天室的所有者
+    let mut alice = join(&server, "alice", "lobby").await;
+    let mut bob = join(&server, "bob", "lobby").await;
+    let mut carol = join(&server, "carol", "lobby").await;
+    assert_event(&alice.next().await, Type::Connect, "bob");
+    assert_event(&alice.next().await, Type::Connect, "carol");
+
+    let moderate = |action: ModerationAction, target_user: &str| ClientMessage {
+        r#type: Type::Moderation.into(),
+        room: "lobby".into(),
+        action: action.into(),
+        target_user: target_user.into(),
+        ..Default::default()
+    };
+    alice
+        .tx
+        .send(moderate(ModerationAction::Mute, "carol"))
+        .await
+        .unwrap();
+    assert_event(&alice.next().await, Type::Moderation, "alice");
+
+    // 被禁言后输入状态被丢弃, 消息被拒绝
+    let typing = ClientMessage {
+        r#type: Type::Typing.into(),
+        room: "lobby".into(),
+        ..Default::default()
+    };
+    carol.tx.send(typing).await.unwrap();
+    carol.send("hello").await;
+    loop {
+        let message = carol.next().await;
+        if message.r#type() == Type::Error {
+            assert_eq!(message.content, "muted in chatroom, message not sent");
+            break;
+        }
+    }
+    alice.send("after mute").await;
+    let message = alice.next().await;
+    assert_event(&message, Type::Message, "alice");
+    assert_eq!(message.content, "after mute");
+
+    alice
+        .tx
+        .send(moderate(ModerationAction::Ban, "bob"))
+        .await
+        .unwrap();
+    bob.until_end().await;
+    let (history, members) = read_room(&mut bob.client, "bob", "lobby").await;
+    assert_eq!(history.unwrap_err().code(), Code::PermissionDenied);
+    assert_eq!(members.unwrap_err().code(), Code::PermissionDenied);
+
+    let (history, members) = read_room(&mut carol.client, "carol", "lobby").await;
+    assert!(history.is_ok() && members.is_ok());
+
+    drop((alice, carol));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn direct_message_needs_content_and_another_recipient() {
+    let server = TestServer::start().await;
+    let mut alice = join(&server, "alice", "lobby").await;
+
+    for (recipient, content, reason) in [
+        ("alice", "hi me", "can't send direct message to yourself"),
+        ("bob", "", "content must not be empty, message not sent"),
+    ] {
+        let direct = ClientMessage {
+            r#type: Type::Message.into(),
+            recipient: recipient.into(),
+            content: content.into(),
+            ..Default::default()
+        };
+        alice.tx.send(direct).await.unwrap();
+        let message = alice.next().await;
+        assert_eq!(message.r#type(), Type::Error);
+        assert_eq!(message.content, reason);
+    }
+
+    drop(alice);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn shutdown_notifies_clients_and_rejects_new_chats() {
+    let server = TestServer::start().await;
+
+    let mut alice = join(&server, "alice", "lobby").await;
+    let mut bob = join(&server, "bob", "kitchen").await;
+
+    let drain = server.drain.clone();
+    let drained = tokio::spawn(async move { drain.drain(TIMEOUT).await });
+
+    // 每个连接最后收到关闭事件, 然后 Chat 流结束
+    for user in [&mut alice, &mut bob] {
+        let messages = user.until_end().await;
+        let last = messages.last().expect("no shutdown message");
+        assert_eq!(last.r#type(), Type::Shutdown, "{messages:?}");
+        assert_eq!(last.content, "server is shutting down");
+    }
+    assert!(drained.await.unwrap(), "sessions not drained in time");
+
+    let mut client = server.client().await;
+    let Err(status) = open_chat(&mut client, &[("username", "carol"), ("chatroom", "lobby")]).await
+    else {
+        panic!("chat should be rejected while shutting down");
+    };
+    assert_eq!(status.code(), Code::Unavailable);
+    assert_eq!(status.message(), "server is shutting down");
+
+    server.stop().await;
+}
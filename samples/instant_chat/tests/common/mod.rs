@@ -0,0 +1,79 @@
+//! Helpers for tests against a local valkey-server (or redis-server).
+//! Such tests are `#[ignore]`d and run with `make test_valkey`; they fail when no server binary
+//! is found in PATH instead of passing without testing anything.
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+pub fn server_binary() -> &'static str {
+    ["valkey-server", "redis-server"]
+        .into_iter()
+        .find(|binary| {
+            Command::new(binary)
+                .arg("--version")
+                .stdout(Stdio::null())
+                .status()
+                .is_ok()
+        })
+        .expect("valkey-server or redis-server not found in PATH")
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// 测试启动的服务端进程, drop 时结束进程, 测试 panic 时也不会遗留进程和端口
+pub struct ServerProcess(Child);
+
+impl ServerProcess {
+    /// 结束进程并等待退出
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn start_server(binary: &str, port: u16) -> ServerProcess {
+    let child = Command::new(binary)
+        .args([
+            "--port",
+            &port.to_string(),
+            "--save",
+            "",
+            "--appendonly",
+            "no",
+        ])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start valkey-server");
+    ServerProcess(child)
+}
+
+pub async fn wait_until_ready(url: &str) {
+    let client = redis::Client::open(url).unwrap();
+    for _ in 0..50 {
+        if let Ok(mut conn) = client.get_multiplexed_async_connection().await
+            && redis::cmd("PING")
+                .query_async::<String>(&mut conn)
+                .await
+                .is_ok()
+        {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("valkey-server did not start");
+}
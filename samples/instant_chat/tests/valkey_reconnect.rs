@@ -0,0 +1,84 @@
+//! Kills and restarts a local valkey-server while a subscriber is listening.
+//! Needs `valkey-server` (or `redis-server`) in PATH, run with `make test_valkey`.
+
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use instant_chat::chat_repository::{
+    ChannelMessage, ChatChannel, ChatRepository, SubscriptionState, is_unavailable,
+};
+use instant_chat::subscriber::{BufferOptions, SubscriberReceiver};
+use instant_chat::valkey_repository::ValkeyRepository;
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+
+use common::{free_port, server_binary, start_server, wait_until_ready};
+
+fn message(content: &str) -> ChannelMessage {
+    ChannelMessage {
+        username: "tester".into(),
+        content: content.into(),
+        ..Default::default()
+    }
+}
+
+async fn next(rx: &mut SubscriberReceiver<Result<ChannelMessage>>) -> Result<ChannelMessage> {
+    timeout(Duration::from_secs(15), rx.recv())
+        .await
+        .expect("timed out waiting for message")
+        .expect("subscription closed")
+}
+
+#[tokio::test]
+#[ignore = "needs valkey-server"]
+async fn resubscribe_after_valkey_restart() {
+    let binary = server_binary();
+    let port = free_port();
+    let url = format!("redis://127.0.0.1:{port}/?protocol=resp3");
+    let server = start_server(binary, port);
+    wait_until_ready(&url).await;
+
+    let repository = ValkeyRepository::new(&url, 100).await.unwrap();
+    let token = CancellationToken::new();
+    let mut rx = repository
+        .subscribe::<Result<ChannelMessage>>("room", BufferOptions::default(), token.clone())
+        .await
+        .unwrap();
+    let mut channel = repository.get_channel("room");
+    channel.publish(&message("before")).await.unwrap();
+    assert_eq!(next(&mut rx).await.unwrap().content, "before");
+
+    server.stop();
+    let err = next(&mut rx).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SubscriptionState>(),
+        Some(&SubscriptionState::Interrupted)
+    );
+    let err = channel.publish(&message("lost")).await.unwrap_err();
+    assert!(is_unavailable(&err), "{err:?}");
+
+    let server = start_server(binary, port);
+    wait_until_ready(&url).await;
+    let err = next(&mut rx).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SubscriptionState>(),
+        Some(&SubscriptionState::Resumed)
+    );
+
+    // 发布连接在后台重连, 重连完成前发布可能失败
+    let mut published = false;
+    for _ in 0..50 {
+        if channel.publish(&message("after")).await.is_ok() {
+            published = true;
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(published, "publish did not recover");
+    assert_eq!(next(&mut rx).await.unwrap().content, "after");
+
+    token.cancel();
+    server.stop();
+}
@@ -0,0 +1,60 @@
+//! Edits messages in a local valkey-server through the update script.
+//! Needs `valkey-server` (or `redis-server`) in PATH, run with `make test_valkey`.
+
+mod common;
+
+use instant_chat::chat_repository::{
+    ChannelMessage, ChatChannel, ChatRepository, HistoryQuery, MessageKind, UpdateRejected,
+};
+use instant_chat::valkey_repository::ValkeyRepository;
+
+use common::{free_port, server_binary, start_server, wait_until_ready};
+
+#[tokio::test]
+#[ignore = "needs valkey-server"]
+async fn update_requires_full_message_id() {
+    let port = free_port();
+    let url = format!("redis://127.0.0.1:{port}/?protocol=resp3");
+    let server = start_server(server_binary(), port);
+    wait_until_ready(&url).await;
+
+    let repository = ValkeyRepository::new(&url, 100).await.unwrap();
+    let mut channel = repository.get_channel("room");
+    let hello = ChannelMessage {
+        username: "tester".into(),
+        content: "hello".into(),
+        ..Default::default()
+    };
+    channel.publish(&hello).await.unwrap();
+    let history = repository
+        .history("room", &HistoryQuery::latest(1))
+        .await
+        .unwrap();
+    let id = history[0].id.clone();
+    let edit = |target_id: &str| ChannelMessage {
+        kind: MessageKind::Edit,
+        username: "tester".into(),
+        content: "edited".into(),
+        target_id: target_id.into(),
+        ..Default::default()
+    };
+
+    // 只有 <millis> 的 ID 与内存后端一样找不到消息
+    let (millis, _) = id.split_once('-').unwrap();
+    let err = channel.update(&edit(millis), false).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<UpdateRejected>(),
+        Some(&UpdateRejected::NotFound)
+    );
+
+    let event = channel.update(&edit(&id), false).await.unwrap();
+    assert_eq!(event.target_id, id);
+    let history = repository
+        .history("room", &HistoryQuery::latest(10))
+        .await
+        .unwrap();
+    assert_eq!(history[0].content, "edited");
+    assert!(history[0].edited);
+
+    server.stop();
+}